@@ -1,54 +1,622 @@
+use crate::diff::unified_diff;
+use crate::state::{AgentStateLocked, FileState};
 use async_trait::async_trait;
-use protocol::{CommandSpec, FileSpec, HostSpec, TaskError, TaskResult};
+use protocol::{Change, CommandSpec, FileSpec, HostSpec, HostState, TaskError, TaskResult, Umask};
+use reqwest::{
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    StatusCode,
+};
+use sha2::{Digest, Sha256};
 use shellexpand::tilde;
-use std::path::Path;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
 use tokio::{
-    fs::{File, OpenOptions},
-    io::{AsyncReadExt, AsyncWriteExt},
+    fs::File,
+    io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt},
+    sync::{Mutex, OwnedMutexGuard},
 };
+use uuid::Uuid;
+
+/// default cap of captured bytes per output stream
+const DEFAULT_OUTPUT_LIMIT: usize = 64 * 1024;
 
-pub type AsyncTask = Box<dyn AsyncTaskTrait + Send + Sync>;
+/// default cap of downloaded bytes per file
+const DEFAULT_DOWNLOAD_LIMIT: u64 = 256 * 1024 * 1024;
+
+pub type AsyncTask = Arc<dyn AsyncTaskTrait + Send + Sync>;
 pub type AsyncTaskResult = Result<TaskResult, TaskError>;
 
 #[async_trait]
 pub trait AsyncTaskTrait {
     async fn run(&self) -> AsyncTaskResult;
+
+    /// command to run after a run changed something
+    fn on_change(&self) -> Option<&CommandSpec> {
+        None
+    }
 }
 
 pub struct FileUpdateTask {
     pub file_spec: FileSpec,
+    pub id: Uuid,
+    /// validators of the last fetch
+    pub state: AgentStateLocked,
+}
+
+/// a file next to its target that is removed when dropped, unless it was
+/// renamed over the target
+struct TempFile {
+    path: Option<PathBuf>,
+}
+
+impl TempFile {
+    fn next_to(target: &Path) -> Self {
+        let name = target
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let path = target.with_file_name(format!(".{}.{}.tmp", name, Uuid::new_v4().simple()));
+        Self { path: Some(path) }
+    }
+
+    fn path(&self) -> &Path {
+        self.path.as_deref().expect("temp file not persisted yet")
+    }
+
+    /// atomically replace `target` with the temp file
+    async fn persist(mut self, target: &Path) -> io::Result<()> {
+        tokio::fs::rename(self.path(), target).await?;
+        self.path = None;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// hex sha256 of a file, None if it does not exist
+async fn file_sha256(path: &Path) -> io::Result<Option<String>> {
+    let mut file = match File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut hasher = Sha256::new();
+    let mut chunk = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&chunk[..n]);
+    }
+    Ok(Some(format!("{:x}", hasher.finalize())))
+}
+
+fn net_error(e: reqwest::Error) -> TaskError {
+    TaskError::NetError(e.to_string())
+}
+
+/// mode and ownership a file is given, None leaves it as created
+#[derive(Default)]
+struct FileAttrs {
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+impl FileAttrs {
+    /// the mode and, as root, the ownership of the `current` file that is
+    /// replaced
+    #[cfg(unix)]
+    fn preserve(current: Option<&std::fs::Metadata>) -> Self {
+        use std::os::unix::fs::MetadataExt;
+
+        // only root can give the file away
+        let owner = current.filter(|_| unsafe { libc::geteuid() } == 0);
+        Self {
+            mode: current.map(|m| m.mode() & 0o7777),
+            uid: owner.map(|m| m.uid()),
+            gid: owner.map(|m| m.gid()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn preserve(_current: Option<&std::fs::Metadata>) -> Self {
+        Self::default()
+    }
+
+    #[cfg(unix)]
+    fn set(&self, path: &Path) -> io::Result<()> {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let meta = std::fs::metadata(path)?;
+        // chown first, it may clear the setuid bits
+        if self.uid.is_some_and(|u| u != meta.uid()) || self.gid.is_some_and(|g| g != meta.gid()) {
+            std::os::unix::fs::chown(path, self.uid, self.gid)?;
+        }
+        if let Some(mode) = self.mode {
+            if meta.mode() & 0o7777 != mode {
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn set(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// create a new file with the mode it is given, so that it is never
+    /// readable by more than that in the meantime
+    async fn create(&self, path: &Path) -> io::Result<File> {
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            options.mode(mode);
+        }
+        options.open(path).await
+    }
+}
+
+/// `<dir>/<name>.` prefix of the backups of a file
+fn backup_prefix(path: &Path) -> (PathBuf, String) {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    (dir, format!("{}.", name))
+}
+
+/// utc timestamp and counter of a backup named
+/// `<prefix><timestamp>[-<counter>].bak`
+fn backup_order(name: &str, prefix: &str) -> Option<(u64, u64)> {
+    let rest = name.strip_prefix(prefix)?.strip_suffix(".bak")?;
+    let (ts, n) = rest.split_once('-').unwrap_or((rest, "0"));
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if ts.len() != 17 || !digits(ts) || !digits(n) {
+        return None;
+    }
+    Some((ts.parse().ok()?, n.parse().ok()?))
+}
+
+/// backups of a file, oldest first
+async fn list_backups(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let (dir, prefix) = backup_prefix(path);
+    let mut backups = vec![];
+    let mut entries = tokio::fs::read_dir(&dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(order) = backup_order(&name, &prefix) {
+            backups.push((order, dir.join(name)));
+        }
+    }
+    backups.sort();
+    Ok(backups.into_iter().map(|(_, backup)| backup).collect())
+}
+
+/// keep the current version of a file as a backup, dropping all but the
+/// latest `keep` backups
+async fn backup_file(path: &Path, keep: usize) -> io::Result<()> {
+    let (dir, prefix) = backup_prefix(path);
+    let timestamp = chrono::Utc::now().format("%Y%m%d%H%M%S%3f");
+    // backups taken within the same millisecond are told apart by a counter
+    let mut backup = dir.join(format!("{}{}.bak", prefix, timestamp));
+    let mut n = 0;
+    while tokio::fs::symlink_metadata(&backup).await.is_ok() {
+        n += 1;
+        backup = dir.join(format!("{}{}-{}.bak", prefix, timestamp, n));
+    }
+    // the file is about to be replaced, so a link keeps it as it is
+    if tokio::fs::hard_link(path, &backup).await.is_err() {
+        tokio::fs::copy(path, &backup).await?;
+    }
+
+    let backups = list_backups(path).await?;
+    let stale = backups.len().saturating_sub(keep);
+    for old in &backups[..stale] {
+        tokio::fs::remove_file(old).await?;
+    }
+    Ok(())
+}
+
+/// restore the latest backup of the file of `spec`, the backup is used up
+/// so that rolling back again goes one more version back
+pub async fn rollback_file(spec: &FileSpec) -> AsyncTaskResult {
+    let path = PathBuf::from(tilde(&spec.path).as_ref());
+    let Some(backup) = list_backups(&path).await?.pop() else {
+        return Err(TaskError::NoBackup(path.display().to_string()));
+    };
+    tokio::fs::rename(&backup, &path).await?;
+    Ok(TaskResult {
+        status: Some(0),
+        message: format!("restored {}", backup.display()),
+        change: Some(Change::Updated),
+        ..Default::default()
+    })
+}
+
+impl FileUpdateTask {
+    /// mode and ownership of the spec, those of the `current` file are kept
+    /// for what is not set
+    #[cfg(unix)]
+    fn attrs(&self, current: Option<&std::fs::Metadata>) -> Result<FileAttrs, TaskError> {
+        let mut uid = None;
+        let mut gid = None;
+        if let Some(owner) = &self.file_spec.owner {
+            let (u, g) = lookup_user(owner)?;
+            uid = Some(u);
+            gid = g;
+        }
+        if let Some(group) = &self.file_spec.group {
+            gid = Some(lookup_group(group)?);
+        }
+
+        let kept = FileAttrs::preserve(current);
+        Ok(FileAttrs {
+            mode: self.file_spec.mode.map(|m| m.0).or(kept.mode),
+            uid: uid.or(kept.uid),
+            gid: gid.or(kept.gid),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn attrs(&self, _current: Option<&std::fs::Metadata>) -> Result<FileAttrs, TaskError> {
+        let spec = &self.file_spec;
+        if spec.mode.is_some() || spec.owner.is_some() || spec.group.is_some() {
+            return Err(TaskError::UnsupportedPlatform(format!(
+                "mode, owner and group on {}",
+                std::env::consts::OS
+            )));
+        }
+        Ok(FileAttrs::default())
+    }
+
+    /// request the content, conditionally on the validators of `last` if any;
+    /// None if it was not modified
+    async fn fetch(
+        &self,
+        last: Option<&FileState>,
+    ) -> Result<Option<reqwest::Response>, TaskError> {
+        let mut req = reqwest::Client::new().get(&self.file_spec.url);
+        if let Some(last) = last {
+            if let Some(etag) = &last.etag {
+                req = req.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &last.last_modified {
+                req = req.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let resp = req.send().await.map_err(net_error)?;
+        let status = resp.status();
+        if status == StatusCode::NOT_MODIFIED && last.is_some() {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            return Err(TaskError::HttpStatus(status.as_u16()));
+        }
+        Ok(Some(resp))
+    }
+
+    /// stream the content into `file`, returning its hex sha256 and size
+    async fn download(
+        &self,
+        mut resp: reqwest::Response,
+        file: &mut File,
+    ) -> Result<(String, u64), TaskError> {
+        let max = self.file_spec.max_size.unwrap_or(DEFAULT_DOWNLOAD_LIMIT);
+        if resp.content_length().is_some_and(|len| len > max) {
+            return Err(TaskError::TooLarge(max));
+        }
+
+        let mut hasher = Sha256::new();
+        let mut size = 0u64;
+        while let Some(chunk) = resp.chunk().await.map_err(net_error)? {
+            size += chunk.len() as u64;
+            if size > max {
+                return Err(TaskError::TooLarge(max));
+            }
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+        file.sync_all().await?;
+        Ok((format!("{:x}", hasher.finalize()), size))
+    }
 }
 
 #[async_trait]
 impl AsyncTaskTrait for FileUpdateTask {
+    /// download to a temp file and rename it over the target once complete
+    /// and verified, so that the target is never left partially written;
+    /// the target is left alone when the content did not change
     async fn run(&self) -> AsyncTaskResult {
-        let resp = match reqwest::get(&self.file_spec.url).await {
-            Ok(resp) => resp,
-            Err(e) => {
-                return Err(TaskError::NetError(e.to_string()));
+        let path = PathBuf::from(tilde(&self.file_spec.path).as_ref());
+        if self.file_spec.create_parent_dirs {
+            if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                tokio::fs::create_dir_all(dir).await?;
             }
+        }
+        let current = file_sha256(&path).await?;
+        let metadata = match tokio::fs::metadata(&path).await {
+            Ok(meta) => Some(meta),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
         };
-        let bytes = match resp.bytes().await {
-            Ok(bytes) => bytes,
-            Err(e) => {
-                return Err(TaskError::NetError(e.to_string()));
+        let attrs = self.attrs(metadata.as_ref())?;
+
+        // only trust the validators while the target still holds what we
+        // fetched with them, and the url is the same
+        let last = self
+            .state
+            .lock()
+            .await
+            .file(&self.id)
+            .cloned()
+            .filter(|last| {
+                last.url == self.file_spec.url && current.as_ref() == Some(&last.sha256)
+            });
+        let Some(resp) = self.fetch(last.as_ref()).await? else {
+            attrs.set(&path)?;
+            return Ok(TaskResult {
+                status: Some(0),
+                message: "not modified".to_string(),
+                change: Some(Change::Unchanged),
+                ..Default::default()
+            });
+        };
+
+        let header = |name| {
+            resp.headers()
+                .get(name)
+                .and_then(|v: &reqwest::header::HeaderValue| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+
+        let temp = TempFile::next_to(&path);
+        let mut file = attrs.create(temp.path()).await?;
+        let (sha256, size) = self.download(resp, &mut file).await?;
+        drop(file);
+
+        if let Some(expected) = &self.file_spec.sha256 {
+            if !expected.eq_ignore_ascii_case(&sha256) {
+                return Err(TaskError::ChecksumMismatch {
+                    expected: expected.clone(),
+                    actual: sha256,
+                });
+            }
+        }
+
+        let change = if current.as_ref() == Some(&sha256) {
+            attrs.set(&path)?;
+            Change::Unchanged
+        } else {
+            attrs.set(temp.path())?;
+            if current.is_some() && self.file_spec.backup > 0 {
+                backup_file(&path, self.file_spec.backup).await?;
             }
+            temp.persist(&path).await?;
+            Change::Updated
         };
 
-        let path = tilde(&self.file_spec.path);
-        let mut file = File::create(path.as_ref()).await?;
-        file.write_all(&bytes).await?;
+        let state = FileState {
+            url: self.file_spec.url.clone(),
+            etag,
+            last_modified,
+            sha256: sha256.clone(),
+        };
+        self.state.lock().await.set_file(self.id, state).await;
         Ok(TaskResult {
             status: Some(0),
-            message: "".to_string(),
+            message: format!("sha256 {}, {} bytes", sha256, size),
+            change: Some(change),
+            ..Default::default()
         })
     }
+
+    fn on_change(&self) -> Option<&CommandSpec> {
+        self.file_spec.on_change.as_ref()
+    }
 }
 
 pub struct CommandTask {
     pub command_spec: CommandSpec,
 }
 
+/// kills the whole process group of a spawned command when dropped, unless
+/// disarmed, so that neither a timeout nor an aborted run leaves processes behind
+struct ProcessGroupGuard {
+    pid: Option<u32>,
+}
+
+impl ProcessGroupGuard {
+    fn disarm(&mut self) {
+        self.pid = None;
+    }
+}
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        let Some(pid) = self.pid else {
+            return;
+        };
+        log::warn!("Killing process group of {}", pid);
+
+        #[cfg(unix)]
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+        }
+
+        #[cfg(windows)]
+        let _ = std::process::Command::new("taskkill")
+            .args(["/F", "/T", "/PID", &pid.to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    }
+}
+
+/// resolve a user name or uid into the uid and its primary gid
+#[cfg(unix)]
+fn lookup_user(user: &str) -> Result<(u32, Option<u32>), TaskError> {
+    let not_found = || TaskError::UserNotFound(user.to_string());
+
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut result = std::ptr::null_mut();
+    let ret = match user.parse::<u32>() {
+        Ok(uid) => unsafe {
+            libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result)
+        },
+        Err(_) => {
+            let name = std::ffi::CString::new(user).map_err(|_| not_found())?;
+            unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                )
+            }
+        }
+    };
+
+    if ret == 0 && !result.is_null() {
+        return Ok((pwd.pw_uid, Some(pwd.pw_gid)));
+    }
+    // a bare uid without passwd entry is still usable
+    match user.parse::<u32>() {
+        Ok(uid) => Ok((uid, None)),
+        Err(_) => Err(not_found()),
+    }
+}
+
+/// resolve a group name or gid
+#[cfg(unix)]
+fn lookup_group(group: &str) -> Result<u32, TaskError> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(gid);
+    }
+    let not_found = || TaskError::GroupNotFound(group.to_string());
+
+    let name = std::ffi::CString::new(group).map_err(|_| not_found())?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut result = std::ptr::null_mut();
+    let ret = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 || result.is_null() {
+        return Err(not_found());
+    }
+    Ok(grp.gr_gid)
+}
+
+/// read the whole stream, keeping at most `limit` bytes; the rest is drained
+/// so that the child never blocks on a full pipe
+async fn read_capped<R: AsyncRead + Unpin>(mut reader: R, limit: usize) -> io::Result<String> {
+    let mut kept = Vec::new();
+    let mut dropped = 0;
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        let keep = n.min(limit - kept.len());
+        kept.extend_from_slice(&chunk[..keep]);
+        dropped += n - keep;
+    }
+
+    let mut output = String::from_utf8_lossy(&kept).into_owned();
+    if dropped > 0 {
+        output.push_str(&format!("\n[... truncated {} bytes]", dropped));
+    }
+    Ok(output)
+}
+
+impl CommandTask {
+    fn switches_identity(&self) -> bool {
+        self.command_spec.user.is_some() || self.command_spec.group.is_some()
+    }
+
+    /// apply user, group and umask of the spec to the command
+    #[cfg(unix)]
+    fn set_identity(&self, command: &mut std::process::Command) -> Result<(), TaskError> {
+        use std::os::unix::process::CommandExt;
+
+        let mut uid = None;
+        let mut gid = None;
+        if let Some(user) = &self.command_spec.user {
+            let (u, g) = lookup_user(user)?;
+            uid = Some(u);
+            gid = g;
+        }
+        if let Some(group) = &self.command_spec.group {
+            gid = Some(lookup_group(group)?);
+        }
+
+        let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        if euid != 0 && (uid.is_some_and(|u| u != euid) || gid.is_some_and(|g| g != egid)) {
+            return Err(TaskError::PrivilegeDrop(
+                "agent is not running as root".to_string(),
+            ));
+        }
+
+        if let Some(gid) = gid {
+            command.gid(gid);
+        }
+        if let Some(uid) = uid {
+            command.uid(uid);
+        }
+        if let Some(Umask(mask)) = self.command_spec.umask {
+            unsafe {
+                command.pre_exec(move || {
+                    libc::umask(mask as libc::mode_t);
+                    Ok(())
+                });
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn set_identity(&self, _command: &mut std::process::Command) -> Result<(), TaskError> {
+        if self.switches_identity() || self.command_spec.umask.is_some() {
+            return Err(TaskError::UnsupportedPlatform(format!(
+                "user, group and umask on {}",
+                std::env::consts::OS
+            )));
+        }
+        Ok(())
+    }
+}
+
 #[async_trait]
 impl AsyncTaskTrait for CommandTask {
     /// execute command
@@ -77,91 +645,690 @@ impl AsyncTaskTrait for CommandTask {
             };
         };
 
-        let mut child = tokio::process::Command::new(&args[0])
+        let mut command = std::process::Command::new(&args[0]);
+        command
             .current_dir(&self.command_spec.cwd)
             .args(args[1..].iter())
-            .spawn()?;
-        let exit = child.wait().await?;
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        if self.command_spec.clear_env {
+            command.env_clear();
+        }
+        command.envs(&self.command_spec.env);
+        self.set_identity(&mut command)?;
+
+        // run in its own process group so that it can be killed as a whole
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+
+        let mut child = tokio::process::Command::from(command)
+            .spawn()
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::PermissionDenied if self.switches_identity() => {
+                    TaskError::PrivilegeDrop(e.to_string())
+                }
+                _ => e.into(),
+            })?;
+        let mut guard = ProcessGroupGuard { pid: child.id() };
+
+        let limit = self
+            .command_spec
+            .output_limit
+            .unwrap_or(DEFAULT_OUTPUT_LIMIT);
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let output = async {
+            tokio::try_join!(
+                read_capped(stdout, limit),
+                read_capped(stderr, limit),
+                child.wait()
+            )
+        };
+
+        let (stdout, stderr, exit) = match self.command_spec.timeout {
+            Some(secs) => tokio::time::timeout(Duration::from_secs(secs), output)
+                .await
+                .map_err(|_| TaskError::Timeout(secs))??,
+            None => output.await?,
+        };
+        guard.disarm();
+
         Ok(TaskResult {
             status: exit.code(),
-            message: "".to_string(),
+            stdout,
+            stderr,
+            ..Default::default()
         })
     }
 }
 
+/// delimiters of the part of the hosts file the tasks manage
+const HOSTS_BLOCK_BEGIN: &str = "# BEGIN managed by file-agent";
+const HOSTS_BLOCK_END: &str = "# END managed by file-agent";
+
+/// one lock per hosts file of the agent, by canonical path
+static HOSTS_LOCKS: LazyLock<std::sync::Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> =
+    LazyLock::new(Default::default);
+
+/// lock the hosts file at `path` against the other tasks editing it, so that
+/// none of them loses the changes of another
+async fn lock_hosts(path: &Path) -> io::Result<OwnedMutexGuard<()>> {
+    let path = tokio::fs::canonicalize(path).await?;
+    let lock = HOSTS_LOCKS
+        .lock()
+        .expect("hosts locks poisoned")
+        .entry(path)
+        .or_default()
+        .clone();
+    Ok(lock.lock_owned().await)
+}
+
 pub struct HostTask {
     pub host_spec: HostSpec,
 }
 
-#[async_trait]
-impl AsyncTaskTrait for HostTask {
-    /// add host entry to hosts file
-    async fn run(&self) -> AsyncTaskResult {
+impl HostTask {
+    fn path(&self) -> Result<PathBuf, TaskError> {
+        if let Some(path) = &self.host_spec.path {
+            return Ok(PathBuf::from(tilde(path).as_ref()));
+        }
         let platform = std::env::consts::OS;
-        let path = match platform {
-            "windows" => Path::new("C:\\Windows\\System32\\drivers\\etc\\hosts"),
-            "linux" => Path::new("/etc/hosts"),
-            _ => {
-                return Err(TaskError::UnsupportedPlatform(platform.to_string()));
-            }
-        };
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&path)
-            .await?;
-        let mut content = String::new();
-        file.read_to_string(&mut content).await?;
-
-        let mut lines = content.lines();
-        let mut new_content = String::new();
+        match platform {
+            "windows" => Ok(PathBuf::from("C:\\Windows\\System32\\drivers\\etc\\hosts")),
+            "linux" => Ok(PathBuf::from("/etc/hosts")),
+            _ => Err(TaskError::UnsupportedPlatform(platform.to_string())),
+        }
+    }
+
+    /// apply the entry of the spec to the lines of the managed block; a
+    /// present host is moved to the ip of the spec, an absent entry without
+    /// hosts removes the whole line of its ip
+    fn apply(&self, block: &[&str]) -> Vec<String> {
+        let spec = &self.host_spec;
+        let present = spec.state == HostState::Present;
+
+        let mut lines = vec![];
         let mut found = false;
-        let line_ending = if content.contains("\r\n") {
-            "\r\n"
-        } else {
-            "\n"
-        };
-        while let Some(line) = lines.next() {
-            if line.trim_start().starts_with(&self.host_spec.ip) {
+        for line in block {
+            let mut fields = line.split_ascii_whitespace();
+            let Some(ip) = fields.next().filter(|ip| !ip.starts_with('#')) else {
+                lines.push(line.to_string());
+                continue;
+            };
+            let mut hosts: Vec<&str> = fields.collect();
+            let count = hosts.len();
+
+            if ip == spec.ip {
                 found = true;
-                new_content.push_str(&self.host_spec.ip);
-                let origin: Vec<&str> = line.split_ascii_whitespace().skip(1).collect();
-                for host in &origin {
-                    new_content.push_str(" ");
-                    new_content.push_str(host);
+                if present {
+                    for host in &spec.hosts {
+                        if !hosts.contains(&host.as_str()) {
+                            hosts.push(host);
+                        }
+                    }
+                } else if spec.hosts.is_empty() {
+                    hosts.clear();
+                } else {
+                    hosts.retain(|h| !spec.hosts.iter().any(|s| s == h));
                 }
-                let keep_hosts: Vec<&str> = self
-                    .host_spec
-                    .hosts
-                    .iter()
-                    .map(|s| s.as_str())
-                    .filter(|h| origin.contains(h))
-                    .collect();
-                for host in keep_hosts {
-                    new_content.push_str(" ");
-                    new_content.push_str(host);
+            } else if present {
+                hosts.retain(|h| !spec.hosts.iter().any(|s| s == h));
+            }
+
+            // a line left alone keeps its spacing and comments
+            if count > 0 && hosts.len() == count {
+                lines.push(line.to_string());
+            } else if !hosts.is_empty() {
+                lines.push(format!("{} {}", ip, hosts.join(" ")));
+            }
+        }
+
+        if present && !found && !spec.hosts.is_empty() {
+            lines.push(format!("{} {}", spec.ip, spec.hosts.join(" ")));
+        }
+        lines
+    }
+
+    /// the hosts file with the managed block updated, the block is added at
+    /// the end and removed once empty; the rest of the file is kept as it is,
+    /// line endings included, and a block without its end line is refused
+    fn update(&self, content: &str) -> io::Result<String> {
+        // lines with their line ending, the last one may have none
+        let lines: Vec<&str> = content.split_inclusive('\n').collect();
+        let begin = lines.iter().position(|l| l.trim() == HOSTS_BLOCK_BEGIN);
+        let (begin, end) = match begin {
+            Some(begin) => match lines[begin..]
+                .iter()
+                .position(|l| l.trim() == HOSTS_BLOCK_END)
+            {
+                Some(end) => (begin, Some(begin + end)),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("\"{}\" without \"{}\"", HOSTS_BLOCK_BEGIN, HOSTS_BLOCK_END),
+                    ))
                 }
-                new_content.push_str(line_ending);
-            } else {
+            },
+            None => (lines.len(), None),
+        };
+
+        let old_block: Vec<&str> = match end {
+            Some(end) => lines[begin + 1..end]
+                .iter()
+                .map(|l| split_line_ending(l).0)
+                .collect(),
+            None => vec![],
+        };
+        let block = self.apply(&old_block);
+        if block == old_block {
+            return Ok(content.to_string());
+        }
+
+        // written lines take the line ending of the block, or of the file
+        let eol = lines
+            .get(begin)
+            .or(lines.first())
+            .map_or("", |l| split_line_ending(l).1);
+        let eol = if eol.is_empty() { "\n" } else { eol };
+        // and the file keeps ending with a newline or not
+        let last_eol = match end {
+            Some(end) => split_line_ending(lines[end]).1,
+            None if content.is_empty() || content.ends_with('\n') => eol,
+            None => "",
+        };
+
+        let mut new_content = lines[..begin].concat();
+        if !block.is_empty() {
+            if !new_content.is_empty() && !new_content.ends_with('\n') {
+                new_content.push_str(eol);
+            }
+            new_content.push_str(HOSTS_BLOCK_BEGIN);
+            new_content.push_str(eol);
+            for line in &block {
                 new_content.push_str(line);
-                new_content.push_str(line_ending);
+                new_content.push_str(eol);
             }
+            new_content.push_str(HOSTS_BLOCK_END);
+            new_content.push_str(last_eol);
         }
+        if let Some(end) = end {
+            new_content.push_str(&lines[end + 1..].concat());
+        }
+        Ok(new_content)
+    }
+}
 
-        // add a new host entry
-        if !found {
-            new_content.push_str(&self.host_spec.ip);
-            for host in &self.host_spec.hosts {
-                new_content.push_str(" ");
-                new_content.push_str(host);
-            }
-            new_content.push_str(line_ending);
+/// a line split by `split_inclusive('\n')` into its text and line ending
+fn split_line_ending(line: &str) -> (&str, &str) {
+    let text = line
+        .strip_suffix('\n')
+        .map_or(line, |l| l.strip_suffix('\r').unwrap_or(l));
+    line.split_at(text.len())
+}
+
+#[async_trait]
+impl AsyncTaskTrait for HostTask {
+    /// update the entry of the spec in the managed block of the hosts file,
+    /// replacing the file atomically; the result message is a diff
+    async fn run(&self) -> AsyncTaskResult {
+        let path = self.path()?;
+        let path = path.as_path();
+        // held from reading the file to replacing it
+        let _lock = lock_hosts(path).await?;
+        let content = tokio::fs::read_to_string(path).await?;
+        let new_content = self.update(&content)?;
+        let name = path.display().to_string();
+        if new_content == content || self.host_spec.dry_run {
+            return Ok(TaskResult {
+                status: Some(0),
+                message: unified_diff(&content, &new_content, &name, &name),
+                change: Some(Change::Unchanged),
+                ..Default::default()
+            });
         }
 
+        let metadata = tokio::fs::metadata(path).await?;
+        let attrs = FileAttrs::preserve(Some(&metadata));
+        let temp = TempFile::next_to(path);
+        let mut file = attrs.create(temp.path()).await?;
         file.write_all(new_content.as_bytes()).await?;
+        file.sync_all().await?;
+        drop(file);
+        attrs.set(temp.path())?;
+
+        if let Err(e) = temp.persist(path).await {
+            // a bind mounted file, as the hosts file of a container, cannot
+            // be replaced, only rewritten
+            if e.kind() != io::ErrorKind::ResourceBusy {
+                return Err(e.into());
+            }
+            log::warn!("Cannot replace {}, rewrite it in place", path.display());
+            tokio::fs::write(path, &new_content).await?;
+        }
+
         Ok(TaskResult {
             status: Some(0),
-            message: "".to_string(),
+            message: unified_diff(&content, &new_content, &name, &name),
+            change: Some(Change::Updated),
+            ..Default::default()
         })
     }
+
+    fn on_change(&self) -> Option<&CommandSpec> {
+        self.host_spec.on_change.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backup_names() {
+        let prefix = "hosts.";
+        assert_eq!(
+            backup_order("hosts.20260101120000123.bak", prefix),
+            Some((20260101120000123, 0))
+        );
+        assert_eq!(
+            backup_order("hosts.20260101120000123-2.bak", prefix),
+            Some((20260101120000123, 2))
+        );
+        for name in [
+            "hosts.2026010112000012.bak",
+            "hosts.20260101120000123-.bak",
+            "hosts.20260101120000123-x.bak",
+            "hosts.20260101120000123.tmp",
+            "other.20260101120000123.bak",
+        ] {
+            assert_eq!(backup_order(name, prefix), None, "{}", name);
+        }
+    }
+
+    /// replace the file as the tasks do, backups are links to the old one
+    async fn replace(path: &Path, content: &str) {
+        let temp = TempFile::next_to(path);
+        tokio::fs::write(temp.path(), content).await.unwrap();
+        temp.persist(path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn backups_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        for i in 0..12 {
+            replace(&path, &i.to_string()).await;
+            backup_file(&path, 20).await.unwrap();
+        }
+        replace(&path, "new").await;
+
+        // several backups share a millisecond, the latest still sorts last
+        let backups = list_backups(&path).await.unwrap();
+        assert_eq!(backups.len(), 12);
+        for (i, backup) in backups.iter().enumerate() {
+            assert_eq!(std::fs::read_to_string(backup).unwrap(), i.to_string());
+        }
+
+        backup_file(&path, 3).await.unwrap();
+        let backups = list_backups(&path).await.unwrap();
+        let contents: Vec<_> = backups
+            .iter()
+            .map(|b| std::fs::read_to_string(b).unwrap())
+            .collect();
+        assert_eq!(contents, ["10", "11", "new"]);
+    }
+
+    fn hosts(ip: &str, hosts: &[&str], state: HostState) -> HostTask {
+        HostTask {
+            host_spec: HostSpec {
+                ip: ip.to_string(),
+                hosts: hosts.iter().map(|h| h.to_string()).collect(),
+                state,
+                path: None,
+                dry_run: false,
+                on_change: None,
+            },
+        }
+    }
+
+    fn block(lines: &[&str]) -> String {
+        let mut block = format!("{}\n", HOSTS_BLOCK_BEGIN);
+        for line in lines {
+            block.push_str(line);
+            block.push('\n');
+        }
+        block.push_str(HOSTS_BLOCK_END);
+        block.push('\n');
+        block
+    }
+
+    #[test]
+    fn hosts_add() {
+        let task = hosts("10.0.0.1", &["db"], HostState::Present);
+        let content = "127.0.0.1 localhost\n";
+        let updated = task.update(content).unwrap();
+        assert_eq!(updated, format!("{}{}", content, block(&["10.0.0.1 db"])));
+        // applying again changes nothing
+        assert_eq!(task.update(&updated).unwrap(), updated);
+
+        let task = hosts("10.0.0.1", &["cache"], HostState::Present);
+        assert_eq!(
+            task.update(&updated).unwrap(),
+            format!("{}{}", content, block(&["10.0.0.1 db cache"]))
+        );
+    }
+
+    #[test]
+    fn hosts_replace() {
+        let content = format!(
+            "127.0.0.1 localhost\n{}# after\n",
+            block(&["# pinned", "10.0.0.1 db cache"])
+        );
+        let task = hosts("10.0.0.2", &["db"], HostState::Present);
+        assert_eq!(
+            task.update(&content).unwrap(),
+            format!(
+                "127.0.0.1 localhost\n{}# after\n",
+                block(&["# pinned", "10.0.0.1 cache", "10.0.0.2 db"])
+            )
+        );
+    }
+
+    #[test]
+    fn hosts_absent() {
+        let content = format!("127.0.0.1 localhost\n{}", block(&["10.0.0.1 db cache"]));
+        let task = hosts("10.0.0.1", &["db"], HostState::Absent);
+        assert_eq!(
+            task.update(&content).unwrap(),
+            format!("127.0.0.1 localhost\n{}", block(&["10.0.0.1 cache"]))
+        );
+        // the block goes away with its last line
+        let task = hosts("10.0.0.1", &[], HostState::Absent);
+        assert_eq!(task.update(&content).unwrap(), "127.0.0.1 localhost\n");
+        // nothing to remove from a file without the block
+        let content = "127.0.0.1 localhost";
+        assert_eq!(task.update(content).unwrap(), content);
+    }
+
+    #[test]
+    fn hosts_missing_end() {
+        let content = format!("127.0.0.1 localhost\n{}\n10.0.0.1 db\n", HOSTS_BLOCK_BEGIN);
+        let task = hosts("10.0.0.2", &["cache"], HostState::Present);
+        let e = task.update(&content).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn hosts_line_endings() {
+        let task = hosts("10.0.0.1", &["db"], HostState::Present);
+
+        // a crlf file gets a crlf block, the lf line it has is kept
+        let content = "127.0.0.1 localhost\r\n::1 localhost\n";
+        assert_eq!(
+            task.update(content).unwrap(),
+            format!(
+                "{}{}\r\n10.0.0.1 db\r\n{}\r\n",
+                content, HOSTS_BLOCK_BEGIN, HOSTS_BLOCK_END
+            )
+        );
+
+        // an unchanged file is kept byte for byte
+        let content = format!(
+            "127.0.0.1 localhost\n{}\r\n10.0.0.1  db\r\n{}",
+            HOSTS_BLOCK_BEGIN, HOSTS_BLOCK_END
+        );
+        assert_eq!(task.update(&content).unwrap(), content);
+
+        // no trailing newline before or after
+        let content = "127.0.0.1 localhost";
+        assert_eq!(
+            task.update(content).unwrap(),
+            format!(
+                "{}\n{}\n10.0.0.1 db\n{}",
+                content, HOSTS_BLOCK_BEGIN, HOSTS_BLOCK_END
+            )
+        );
+        let task = hosts("10.0.0.2", &["cache"], HostState::Present);
+        let content = format!(
+            "127.0.0.1 localhost\n{}\n10.0.0.1 db\n{}",
+            HOSTS_BLOCK_BEGIN, HOSTS_BLOCK_END
+        );
+        assert_eq!(
+            task.update(&content).unwrap(),
+            format!(
+                "127.0.0.1 localhost\n{}\n10.0.0.1 db\n10.0.0.2 cache\n{}",
+                HOSTS_BLOCK_BEGIN, HOSTS_BLOCK_END
+            )
+        );
+    }
+
+    /// a task editing the hosts file at `path`
+    fn hosts_at(path: &Path, ip: &str, names: &[&str], state: HostState) -> HostTask {
+        let mut task = hosts(ip, names, state);
+        task.host_spec.path = Some(path.display().to_string());
+        task
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn hosts_tasks_serialized() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hosts");
+        std::fs::write(&path, "127.0.0.1 localhost\n").unwrap();
+
+        let runs: Vec<_> = (0..20)
+            .map(|i| {
+                let task = hosts_at(
+                    &path,
+                    "10.0.0.1",
+                    &[&format!("host{}", i)],
+                    HostState::Present,
+                );
+                tokio::spawn(async move { task.run().await.unwrap() })
+            })
+            .collect();
+        for run in runs {
+            run.await.unwrap();
+        }
+
+        // no task lost the host of another
+        let content = std::fs::read_to_string(&path).unwrap();
+        for i in 0..20 {
+            assert!(content.contains(&format!(" host{}", i)), "{}", content);
+        }
+    }
+
+    #[tokio::test]
+    async fn hosts_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hosts");
+        let original = "127.0.0.1 localhost\n";
+        std::fs::write(&path, original).unwrap();
+        let read = || std::fs::read_to_string(&path).unwrap();
+
+        // insert the block
+        let task = hosts_at(&path, "10.0.0.1", &["db"], HostState::Present);
+        let result = task.run().await.unwrap();
+        assert!(matches!(result.change, Some(Change::Updated)));
+        assert_eq!(read(), format!("{}{}", original, block(&["10.0.0.1 db"])));
+        assert!(
+            result.message.contains("\n+10.0.0.1 db\n"),
+            "{}",
+            result.message
+        );
+
+        // nothing to do the second time
+        let result = task.run().await.unwrap();
+        assert!(matches!(result.change, Some(Change::Unchanged)));
+        assert_eq!(result.message, "");
+
+        // update a line of the block
+        let task = hosts_at(&path, "10.0.0.1", &["cache"], HostState::Present);
+        let result = task.run().await.unwrap();
+        assert!(matches!(result.change, Some(Change::Updated)));
+        assert_eq!(
+            read(),
+            format!("{}{}", original, block(&["10.0.0.1 db cache"]))
+        );
+        assert!(result
+            .message
+            .contains("\n-10.0.0.1 db\n+10.0.0.1 db cache\n"));
+
+        // the block goes with its last line
+        let task = hosts_at(&path, "10.0.0.1", &[], HostState::Absent);
+        let result = task.run().await.unwrap();
+        assert!(matches!(result.change, Some(Change::Updated)));
+        assert_eq!(read(), original);
+    }
+
+    #[tokio::test]
+    async fn hosts_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hosts");
+        let original = b"127.0.0.1 localhost\r\n# kept as is \xc3\xa9\r\n";
+        std::fs::write(&path, original).unwrap();
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+
+        let mut task = hosts_at(&path, "10.0.0.1", &["db"], HostState::Present);
+        task.host_spec.dry_run = true;
+        let result = task.run().await.unwrap();
+        assert!(matches!(result.change, Some(Change::Unchanged)));
+        assert!(
+            result.message.contains("\n+10.0.0.1 db\n"),
+            "{}",
+            result.message
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().modified().unwrap(),
+            modified
+        );
+        // no temp file left behind either
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn read_capped_limit() {
+        assert_eq!(read_capped(&b"abc"[..], 3).await.unwrap(), "abc");
+        assert_eq!(
+            read_capped(&b"abcdef"[..], 4).await.unwrap(),
+            "abcd\n[... truncated 2 bytes]"
+        );
+        assert_eq!(
+            read_capped(&b"abc"[..], 0).await.unwrap(),
+            "\n[... truncated 3 bytes]"
+        );
+    }
+
+    #[tokio::test]
+    async fn read_capped_drains() {
+        use tokio::io::AsyncWriteExt;
+
+        // the writer only finishes if the reader keeps reading past the limit
+        let (reader, mut writer) = tokio::io::duplex(64);
+        let write = tokio::spawn(async move {
+            writer.write_all(&[b'x'; 100_000]).await.unwrap();
+        });
+        let output = read_capped(reader, 10).await.unwrap();
+        write.await.unwrap();
+        assert_eq!(
+            output,
+            format!("{}\n[... truncated 99990 bytes]", "x".repeat(10))
+        );
+    }
+
+    /// a command task running `script` with sh
+    fn command(script: &str) -> CommandTask {
+        CommandTask {
+            command_spec: CommandSpec {
+                cmd: "sh".to_string(),
+                args: vec!["-c".to_string(), script.to_string()],
+                cwd: std::env::temp_dir(),
+                shell: false,
+                output_limit: None,
+                timeout: None,
+                env: Default::default(),
+                clear_env: false,
+                user: None,
+                group: None,
+                umask: None,
+            },
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command_output_capped() {
+        // more than a pipe holds, on both streams
+        let mut task = command("yes | head -c 200000; yes | head -c 200000 >&2");
+        task.command_spec.output_limit = Some(100);
+        let result = task.run().await.unwrap();
+        assert_eq!(result.status, Some(0));
+        let expected = format!("{}\n[... truncated 199900 bytes]", "y\n".repeat(50));
+        assert_eq!(result.stdout, expected);
+        assert_eq!(result.stderr, expected);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command_timeout_kills_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let mut task = command(&format!(
+            "echo $$ > {}; sleep 30 & sleep 30",
+            pid_file.display()
+        ));
+        task.command_spec.timeout = Some(1);
+
+        let start = std::time::Instant::now();
+        let result = task.run().await;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(matches!(result, Err(TaskError::Timeout(1))));
+
+        // the shell leads the group, its background sleep goes with it
+        let pgid: libc::pid_t = std::fs::read_to_string(&pid_file)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        for _ in 0..200 {
+            if unsafe { libc::kill(-pgid, 0) } == -1 {
+                assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::ESRCH));
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("process group {} still alive", pgid);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command_env_and_umask() {
+        let mut task = command("echo $FOO ${HOME:-unset}; umask");
+        task.command_spec.clear_env = true;
+        task.command_spec.env = [("FOO".to_string(), "bar".to_string())].into();
+        task.command_spec.umask = Some(Umask(0o027));
+        let result = task.run().await.unwrap();
+        assert_eq!(result.status, Some(0));
+        assert_eq!(result.stdout, "bar unset\n0027\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn create_with_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret");
+        let attrs = FileAttrs {
+            mode: Some(0o600),
+            ..Default::default()
+        };
+        drop(attrs.create(&path).await.unwrap());
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // never clobbers an existing file
+        assert!(attrs.create(&path).await.is_err());
+    }
 }
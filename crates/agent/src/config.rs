@@ -1,17 +1,75 @@
+use protocol::{FrameLimits, TlsClientConfig};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::{error::Error, fs, io, path::Path};
+use std::path::{Path, PathBuf};
+use std::{error::Error, fs, io};
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub agent_id: Uuid,
     pub server: String,
+    /// pre-shared key, mixed into the key exchange
     pub key: String,
+    /// x25519 private key of the agent, base64
+    pub private_key: String,
+    /// x25519 public key of the server, base64
+    pub server_public_key: String,
+    /// connect to the server over tls
+    #[serde(default)]
+    pub tls: Option<TlsClientConfig>,
+    /// `max_frame_size` and `max_message_size` of the control channel
+    #[serde(flatten)]
+    pub limits: FrameLimits,
+    /// compressions of the frames we accept, best first
+    #[serde(default = "default_compression")]
+    pub compression: Vec<String>,
+    /// frames smaller than this are sent uncompressed, in bytes
+    #[serde(default = "default_compression_threshold")]
+    pub compression_threshold: usize,
     pub pull: bool,
     pub pull_interval: u64,
     pub report: bool,
     pub report_interval: u64,
+    /// event spool file, defaults to `spool.jsonl` next to the tasks file
+    #[serde(default)]
+    pub spool_file: Option<PathBuf>,
+    /// max size of the event spool in bytes
+    #[serde(default = "default_spool_max_size")]
+    pub spool_max_size: u64,
+    /// max age of spooled events in seconds
+    #[serde(default = "default_spool_max_age")]
+    pub spool_max_age: u64,
+    /// what tasks remember across runs, defaults to `state.json` next to the
+    /// tasks file
+    #[serde(default)]
+    pub state_file: Option<PathBuf>,
+    /// keep a persistent connection to get tasks pushed by the server
+    #[serde(default)]
+    pub session: bool,
+    /// heartbeat interval of the session in seconds
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+}
+
+fn default_compression() -> Vec<String> {
+    vec!["zstd".to_string(), "zlib".to_string()]
+}
+
+fn default_compression_threshold() -> usize {
+    256
+}
+
+fn default_heartbeat_interval() -> u64 {
+    30
+}
+
+fn default_spool_max_size() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn default_spool_max_age() -> u64 {
+    7 * 24 * 3600
 }
 
 pub fn load<T: DeserializeOwned, P: AsRef<Path>>(path: P) -> Result<T, Box<dyn Error>> {
@@ -24,6 +82,6 @@ pub fn load<T: DeserializeOwned, P: AsRef<Path>>(path: P) -> Result<T, Box<dyn E
 pub fn dump<P: AsRef<Path>>(cfg: &impl Serialize, path: P) -> Result<(), Box<dyn Error>> {
     let file = fs::File::create(path)?;
     let writer = io::BufWriter::new(file);
-    let u = serde_json::to_writer(writer, cfg)?;
-    Ok(u)
+    serde_json::to_writer(writer, cfg)?;
+    Ok(())
 }
@@ -0,0 +1,147 @@
+use futures::{SinkExt, StreamExt};
+use protocol::{
+    Connector, FrameCodec, FrameError, Greeting, Key, PublicKey, ResponseError, StaticSecret,
+    Verdict,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::ErrorKind;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio_util::codec::Framed;
+use uuid::Uuid;
+
+/// what the agent authenticates itself and the server with
+pub struct Credentials {
+    pub agent_id: Uuid,
+    pub secret: StaticSecret,
+    /// pinned public key of the server
+    pub server_public: PublicKey,
+    /// pre-shared key mixed into the key exchange
+    pub psk: Key,
+}
+
+/// a connection to the server, framed with the keys negotiated with it
+pub struct ServerConn {
+    framed: Framed<protocol::BoxStream, FrameCodec>,
+    /// protocol version negotiated from the greetings
+    version: u16,
+}
+
+fn frame_error(e: FrameError) -> io::Error {
+    match &e {
+        FrameError::Unauthenticated => log::error!("Security: server failed to authenticate"),
+        e if e.is_security() => log::error!("Security: rejected frame from server: {}", e),
+        FrameError::Io(_) => {}
+        e => log::error!("msg decode failed: {}", e),
+    }
+    e.into()
+}
+
+impl ServerConn {
+    /// connect to the server, exchange the greetings and run the key
+    /// exchange, frames are sent with `codec` once it has the negotiated keys;
+    /// a server not knowing us fails with `ResponseError::UnknownAgent`, one
+    /// without our public key with `Verdict::KeyNotRegistered`
+    pub async fn connect(
+        connector: &Connector,
+        server: &str,
+        creds: &Credentials,
+        mut codec: FrameCodec,
+    ) -> io::Result<Self> {
+        let mut stream = connector.connect(server).await?;
+
+        // the server looks up our keys by the id sent in clear
+        let ephemeral = protocol::generate_secret();
+        stream.write_all(&Greeting::ours().to_bytes()).await?;
+        stream.write_all(creds.agent_id.as_bytes()).await?;
+        stream
+            .write_all(PublicKey::from(&ephemeral).as_bytes())
+            .await?;
+        stream.flush().await?;
+
+        let log_reset = |e: &io::Error| {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                log::error!("connection reset during key exchange");
+            }
+        };
+        let mut greeting = [0u8; protocol::GREETING_LEN + 1];
+        stream
+            .read_exact(&mut greeting)
+            .await
+            .inspect_err(log_reset)?;
+        let (greeting, verdict) = greeting.split_at(protocol::GREETING_LEN);
+        let greeting = greeting.try_into().expect("split at greeting length");
+        let Some(greeting) = Greeting::from_bytes(greeting) else {
+            log::error!("server sent no greeting");
+            return Err(ErrorKind::InvalidData.into());
+        };
+        let version = greeting.negotiate();
+        match Verdict::from_u8(verdict[0]) {
+            Some(Verdict::Accepted) => {}
+            Some(Verdict::UnsupportedVersion) => {
+                let e = version
+                    .err()
+                    .unwrap_or_else(|| "unsupported version".to_string());
+                log::error!("Server refused protocol version: {}", e);
+                return Err(io::Error::new(ErrorKind::Unsupported, e));
+            }
+            Some(Verdict::UnknownAgent) => {
+                return Err(io::Error::other(ResponseError::UnknownAgent))
+            }
+            Some(Verdict::KeyNotRegistered) => {
+                return Err(io::Error::other(Verdict::KeyNotRegistered))
+            }
+            None => {
+                log::error!("server sent an unknown verdict {}", verdict[0]);
+                return Err(ErrorKind::InvalidData.into());
+            }
+        }
+        let version = version.map_err(|e| {
+            log::error!("Server accepted an unsupported protocol version: {}", e);
+            io::Error::new(ErrorKind::Unsupported, e)
+        })?;
+
+        let mut server_ephemeral = [0u8; protocol::PUBLIC_KEY_LEN];
+        stream
+            .read_exact(&mut server_ephemeral)
+            .await
+            .inspect_err(log_reset)?;
+
+        let keys = protocol::agent_session_keys(
+            &creds.agent_id,
+            &creds.secret,
+            &ephemeral,
+            &creds.server_public,
+            &PublicKey::from(server_ephemeral),
+            &creds.psk,
+        )
+        .ok_or_else(|| {
+            log::error!("Security: server sent a weak key");
+            io::Error::from(ErrorKind::InvalidData)
+        })?;
+
+        codec.set_keys(vec![keys]);
+        Ok(Self {
+            framed: Framed::new(stream, codec),
+            version,
+        })
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// wait for the next frame
+    pub async fn recv<T: DeserializeOwned>(&mut self) -> io::Result<T> {
+        match self.framed.next().await {
+            Some(frame) => frame.and_then(|frame| frame.parse()).map_err(frame_error),
+            None => {
+                log::error!("connection reset without response");
+                Err(ErrorKind::ConnectionReset.into())
+            }
+        }
+    }
+
+    pub async fn send(&mut self, msg: impl Serialize) -> io::Result<()> {
+        self.framed.send(msg).await.map_err(frame_error)
+    }
+}
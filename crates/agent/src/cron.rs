@@ -1,33 +1,68 @@
+use chrono::{DateTime, Local, SubsecRound};
 pub use cron::Schedule;
+use protocol::CatchUp;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
 use uuid::Uuid;
 
 type DynFuture = dyn Future<Output = ()> + Send;
 type ResFuture = Pin<Box<DynFuture>>;
-type DynFnRetFuture = dyn FnMut() -> ResFuture + Send + Sync;
+type DynFnRetFuture = dyn FnMut(DateTime<Local>, Vec<DateTime<Local>>) -> ResFuture + Send + Sync;
 type AsyncJobLocked = Box<DynFnRetFuture>;
 
+/// longest sleep between two looks at the wall clock; the monotonic clock we
+/// sleep on stops while the machine is suspended, and the wall clock may be
+/// set at any time
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// how late a fire time may come up before it counts as missed, along with
+/// those before the job was added; `run` looks at the clock at least every
+/// `MAX_SLEEP`, so later ones were missed while the machine was suspended or
+/// the clock was set forward
+const MISSED_AFTER: Duration = MAX_SLEEP;
+
 pub struct ScheduledJob {
     schedule: Schedule,
     job: AsyncJobLocked,
     job_id: Uuid,
-    last_run: chrono::DateTime<chrono::Local>,
+    catch_up: CatchUp,
+    /// latest fire time handled
+    last: DateTime<Local>,
+    added: DateTime<Local>,
+    /// next fire time, None once the schedule is exhausted
+    next: Option<DateTime<Local>>,
 }
 
 impl ScheduledJob {
-    pub fn from<F>(schedule: Schedule, f: F) -> Self
+    /// `f` is called with the latest fire time due and the fire times to run
+    /// for, oldest first, which are none if they were all missed and skipped;
+    /// the fire times after `last`, if given, come up right away
+    pub fn from<F>(
+        schedule: Schedule,
+        last: Option<DateTime<Local>>,
+        catch_up: CatchUp,
+        f: F,
+    ) -> Self
     where
         F: 'static,
-        F: FnMut() -> ResFuture + Send + Sync,
+        F: FnMut(DateTime<Local>, Vec<DateTime<Local>>) -> ResFuture + Send + Sync,
     {
+        let added = Local::now();
+        let last = last.unwrap_or(added);
+        let next = schedule.after(&last).next();
         Self {
             schedule,
             job: Box::new(f),
             job_id: Uuid::new_v4(),
-            last_run: chrono::Local::now(),
+            catch_up,
+            last,
+            added,
+            next,
         }
     }
 
@@ -35,45 +70,260 @@ impl ScheduledJob {
         self.job_id
     }
 
-    pub async fn tick(&mut self) {
-        let now = chrono::Local::now();
-        for time in self.schedule.after(&self.last_run) {
-            if time > now {
-                break;
-            }
+    /// run the job for the fire times due by `now`, the missed ones as far as
+    /// `catch_up` allows, and move on to the next one; only the missed fire
+    /// times that may be caught up are looked at, however many there were
+    fn fire(&mut self, now: DateTime<Local>) {
+        // fire times before `bound` were missed: they came before the job was
+        // added, or came up too late to count as on time, and are due; being
+        // whole seconds, those before a time are those before its next second
+        let second = chrono::Duration::seconds(1);
+        let late = now - chrono::Duration::from_std(MISSED_AFTER).expect("in range");
+        let cutoff = self.added.max(late);
+        let mut bound = cutoff.trunc_subsecs(0);
+        if bound < cutoff {
+            bound += second;
+        }
+        let bound = bound.min(now.trunc_subsecs(0) + second);
 
-            let future = (self.job)();
-            tokio::spawn(async move {
-                future.await;
-            });
+        let on_time: Vec<DateTime<Local>> = self
+            .schedule
+            .after(&self.last.max(bound - second))
+            .take_while(|time| *time <= now)
+            .collect();
 
-            self.last_run = time;
+        // the latest missed ones, at least one to tell whether any was missed
+        let limit = match self.catch_up {
+            CatchUp::None | CatchUp::Once => 1,
+            CatchUp::All { max } => max.max(1),
+        };
+        let mut missed: Vec<DateTime<Local>> = self
+            .schedule
+            .after(&bound)
+            .rev()
+            .take_while(|time| *time > self.last)
+            .take(limit)
+            .collect();
+        missed.reverse();
+        let latest = on_time
+            .last()
+            .or(missed.last())
+            .copied()
+            .unwrap_or(self.last);
+
+        let catch_up = match self.catch_up {
+            CatchUp::None => 0,
+            CatchUp::Once => usize::from(!missed.is_empty() && on_time.is_empty()),
+            CatchUp::All { max } => missed.len().min(max),
+        };
+        if !missed.is_empty() {
+            log::info!(
+                "Cron job [{}] missed runs since {}, catching up {}",
+                self.job_id,
+                self.last,
+                catch_up
+            );
         }
+
+        let mut runs = missed.split_off(missed.len() - catch_up);
+        runs.extend(on_time);
+        let future = (self.job)(latest, runs);
+        tokio::spawn(future);
+        self.last = latest;
+        // the next fire time after `now`, past all the missed ones
+        self.next = self.schedule.after(&latest).next();
     }
 }
 
 pub type CronSchedulerLocked = Arc<Mutex<CronScheduler>>;
 
+/// Jobs ordered by their next fire time in a queue, `run` sleeps until the
+/// earliest one and is woken up whenever a job is added or removed.
 pub struct CronScheduler {
-    pub jobs: Vec<ScheduledJob>,
+    jobs: HashMap<Uuid, ScheduledJob>,
+    /// fire times of the jobs; entries of removed jobs, or stale since the
+    /// job fired, are dropped when they come up
+    queue: BinaryHeap<Reverse<(DateTime<Local>, Uuid)>>,
+    wake: Arc<Notify>,
 }
 
 impl CronScheduler {
     pub fn new() -> CronScheduler {
-        CronScheduler { jobs: vec![] }
+        CronScheduler {
+            jobs: HashMap::new(),
+            queue: BinaryHeap::new(),
+            wake: Arc::new(Notify::new()),
+        }
     }
 
     pub fn add(&mut self, job: ScheduledJob) {
-        self.jobs.push(job);
+        if let Some(next) = job.next {
+            self.queue.push(Reverse((next, job.job_id)));
+        }
+        self.jobs.insert(job.job_id, job);
+        self.wake.notify_one();
     }
 
     pub fn remove(&mut self, uuid: Uuid) {
-        self.jobs.retain(|j| j.job_id != uuid);
+        if self.jobs.remove(&uuid).is_some() {
+            self.wake.notify_one();
+        }
     }
 
-    pub async fn tick(&mut self) {
-        for job in &mut self.jobs {
-            job.tick().await;
+    /// fire every job due by `now`, returning the next fire time
+    fn fire_due(&mut self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        while let Some(Reverse((time, id))) = self.queue.peek().copied() {
+            let Some(job) = self.jobs.get_mut(&id).filter(|job| job.next == Some(time)) else {
+                self.queue.pop();
+                continue;
+            };
+            if time > now {
+                return Some(time);
+            }
+
+            self.queue.pop();
+            job.fire(now);
+            if let Some(next) = job.next {
+                self.queue.push(Reverse((next, id)));
+            }
+        }
+        None
+    }
+
+    /// fire the jobs of `sched` on time, forever
+    pub async fn run(sched: CronSchedulerLocked) {
+        let wake = sched.lock().await.wake.clone();
+        loop {
+            let next = sched.lock().await.fire_due(Local::now());
+            let sleep = next.map_or(MAX_SLEEP, |next| {
+                (next - Local::now())
+                    .to_std()
+                    .unwrap_or_default()
+                    .min(MAX_SLEEP)
+            });
+
+            tokio::select! {
+                _ = tokio::time::sleep(sleep) => {}
+                _ = wake.notified() => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    type Fired = Arc<std::sync::Mutex<Vec<(DateTime<Local>, Vec<DateTime<Local>>)>>>;
+
+    /// a job on `expr`, that came up at `last` and was added at `added`
+    fn job(
+        expr: &str,
+        last: DateTime<Local>,
+        added: DateTime<Local>,
+        catch_up: CatchUp,
+    ) -> (ScheduledJob, Fired) {
+        let fired = Fired::default();
+        let record = fired.clone();
+        let mut job = ScheduledJob::from(
+            expr.parse().unwrap(),
+            Some(last),
+            catch_up,
+            move |latest, runs| {
+                record.lock().unwrap().push((latest, runs));
+                Box::pin(async {})
+            },
+        );
+        job.added = added;
+        (job, fired)
+    }
+
+    fn minutes(t: DateTime<Local>, range: std::ops::RangeInclusive<i64>) -> Vec<DateTime<Local>> {
+        range.map(|m| t + chrono::Duration::minutes(m)).collect()
+    }
+
+    /// the fire times a job every minute runs for, for each `catch_up`
+    fn runs(
+        last: DateTime<Local>,
+        added: DateTime<Local>,
+        now: DateTime<Local>,
+        catch_up: CatchUp,
+    ) -> Vec<DateTime<Local>> {
+        let (mut job, fired) = job("0 * * * * *", last, added, catch_up);
+        job.fire(now);
+        let mut fired = fired.lock().unwrap();
+        assert_eq!(fired.len(), 1);
+        let (latest, runs) = fired.pop().unwrap();
+        assert_eq!(job.last, latest);
+        assert_eq!(job.next, Some(latest + chrono::Duration::minutes(1)));
+        runs
+    }
+
+    fn t() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2026, 1, 1, 12, 0, 0).unwrap()
+    }
+
+    fn secs(s: i64) -> chrono::Duration {
+        chrono::Duration::seconds(s)
+    }
+
+    #[tokio::test]
+    async fn on_time() {
+        let (last, added, now) = (t() - secs(60), t() - secs(3600), t() + secs(5));
+        for catch_up in [CatchUp::None, CatchUp::Once, CatchUp::All { max: 3 }] {
+            assert_eq!(runs(last, added, now, catch_up), [t()]);
+        }
+    }
+
+    #[tokio::test]
+    async fn missed_while_down() {
+        // down for 10 minutes, started right after the last fire time
+        let (last, added, now) = (t() - secs(600), t() + secs(30), t() + secs(30));
+        assert!(runs(last, added, now, CatchUp::None).is_empty());
+        assert_eq!(runs(last, added, now, CatchUp::Once), [t()]);
+        assert_eq!(
+            runs(last, added, now, CatchUp::All { max: 3 }),
+            minutes(t(), -2..=0)
+        );
+        assert_eq!(
+            runs(last, added, now, CatchUp::All { max: 20 }),
+            minutes(t(), -9..=0)
+        );
+    }
+
+    #[tokio::test]
+    async fn missed_while_suspended() {
+        // the last 4 fire times came up late, the current one is on time
+        let (last, added, now) = (t() - secs(300), t() - secs(3600), t() + secs(5));
+        assert_eq!(runs(last, added, now, CatchUp::None), [t()]);
+        assert_eq!(runs(last, added, now, CatchUp::Once), [t()]);
+        assert_eq!(
+            runs(last, added, now, CatchUp::All { max: 2 }),
+            minutes(t(), -2..=0)
+        );
+    }
+
+    #[tokio::test]
+    async fn missed_for_long() {
+        // down for a year with a job every second
+        let last = t() - chrono::Duration::days(365);
+        let now = t() + chrono::Duration::milliseconds(500);
+        let seconds = |range: std::ops::RangeInclusive<i64>| range.map(|s| t() + secs(s)).collect();
+        for (catch_up, expected) in [
+            (CatchUp::None, vec![]),
+            (CatchUp::Once, vec![t()]),
+            (CatchUp::All { max: 3 }, seconds(-2..=0)),
+        ] {
+            let (mut job, fired) = job("* * * * * *", last, now, catch_up);
+            let start = std::time::Instant::now();
+            job.fire(now);
+            assert!(start.elapsed() < Duration::from_secs(1));
+
+            let (latest, runs) = fired.lock().unwrap().pop().unwrap();
+            assert_eq!(runs, expected);
+            assert_eq!(latest, t());
+            assert_eq!(job.next, Some(t() + secs(1)));
         }
     }
 }
@@ -0,0 +1,207 @@
+/// lines of context around each change
+const CONTEXT: usize = 3;
+
+enum Op<'a> {
+    Keep(&'a str),
+    Remove(&'a str),
+    Add(&'a str),
+}
+
+/// line by line edit script from `old` to `new`; the common head and tail are
+/// skipped before the quadratic part, which keeps small edits of a large file
+/// cheap
+fn edits<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Op<'a>> {
+    let head = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let tail = old[head..]
+        .iter()
+        .rev()
+        .zip(new[head..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (a, b) = (&old[head..old.len() - tail], &new[head..new.len() - tail]);
+
+    // lcs[i][j]: length of the longest common subsequence of a[i..] and b[j..]
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut ops: Vec<Op> = old[..head].iter().map(|l| Op::Keep(l)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            ops.push(Op::Keep(a[i]));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            ops.push(Op::Remove(a[i]));
+            i += 1;
+        } else {
+            ops.push(Op::Add(b[j]));
+            j += 1;
+        }
+    }
+    ops.extend(old[old.len() - tail..].iter().map(|l| Op::Keep(l)));
+    ops
+}
+
+/// unified diff of two texts, empty if they have the same lines
+pub fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let ops = edits(&old_lines, &new_lines);
+    if ops.iter().all(|op| matches!(op, Op::Keep(_))) {
+        return String::new();
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", old_name, new_name);
+    // positions in `ops` of the changes, grouped into hunks whose context
+    // would overlap
+    let changes: Vec<usize> = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| !matches!(op, Op::Keep(_)))
+        .map(|(k, _)| k)
+        .collect();
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for &k in &changes {
+        let start = k.saturating_sub(CONTEXT);
+        let end = (k + CONTEXT + 1).min(ops.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    for (start, end) in hunks {
+        // line numbers of the hunk start in both texts
+        let mut old_line = 1;
+        let mut new_line = 1;
+        for op in &ops[..start] {
+            match op {
+                Op::Keep(_) => {
+                    old_line += 1;
+                    new_line += 1;
+                }
+                Op::Remove(_) => old_line += 1,
+                Op::Add(_) => new_line += 1,
+            }
+        }
+
+        let hunk = &ops[start..end];
+        let old_len = hunk.iter().filter(|op| !matches!(op, Op::Add(_))).count();
+        let new_len = hunk
+            .iter()
+            .filter(|op| !matches!(op, Op::Remove(_)))
+            .count();
+        // an empty range starts at the line before it
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            if old_len == 0 { old_line - 1 } else { old_line },
+            old_len,
+            if new_len == 0 { new_line - 1 } else { new_line },
+            new_len
+        ));
+        for op in hunk {
+            let (prefix, line) = match op {
+                Op::Keep(l) => (' ', l),
+                Op::Remove(l) => ('-', l),
+                Op::Add(l) => ('+', l),
+            };
+            out.push(prefix);
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(range: std::ops::Range<usize>) -> String {
+        range.map(|i| format!("{}\n", i)).collect()
+    }
+
+    /// the lines of `text` with `f` applied to each
+    fn edit(text: &str, f: impl Fn(&str) -> &str) -> String {
+        text.lines().map(f).flat_map(|l| [l, "\n"]).collect()
+    }
+
+    #[test]
+    fn same_lines() {
+        assert_eq!(unified_diff("a\nb\n", "a\nb\n", "x", "x"), "");
+        assert_eq!(unified_diff("", "", "x", "x"), "");
+    }
+
+    #[test]
+    fn replace_with_context() {
+        let old = numbered(1..10);
+        let new = edit(&old, |l| if l == "5" { "five" } else { l });
+        assert_eq!(
+            unified_diff(&old, &new, "a", "b"),
+            "--- a\n+++ b\n@@ -2,7 +2,7 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n"
+        );
+    }
+
+    #[test]
+    fn add_to_empty() {
+        assert_eq!(
+            unified_diff("", "a\nb\n", "x", "x"),
+            "--- x\n+++ x\n@@ -0,0 +1,2 @@\n+a\n+b\n"
+        );
+    }
+
+    #[test]
+    fn remove_all() {
+        assert_eq!(
+            unified_diff("a\nb\n", "", "x", "x"),
+            "--- x\n+++ x\n@@ -1,2 +0,0 @@\n-a\n-b\n"
+        );
+    }
+
+    #[test]
+    fn append() {
+        let old = numbered(1..6);
+        let new = format!("{}6\n", old);
+        assert_eq!(
+            unified_diff(&old, &new, "x", "x"),
+            "--- x\n+++ x\n@@ -3,3 +3,4 @@\n 3\n 4\n 5\n+6\n"
+        );
+    }
+
+    #[test]
+    fn hunks() {
+        let old = numbered(1..21);
+        // far apart changes make two hunks
+        let new = edit(&old, |l| match l {
+            "2" => "",
+            "18" => "18\neighteen",
+            l => l,
+        })
+        .replace("\n\n", "\n");
+        assert_eq!(
+            unified_diff(&old, &new, "x", "x"),
+            "--- x\n+++ x\n@@ -1,5 +1,4 @@\n 1\n-2\n 3\n 4\n 5\n\
+             @@ -16,5 +15,6 @@\n 16\n 17\n 18\n+eighteen\n 19\n 20\n"
+        );
+        // close ones share one
+        let new = edit(&old, |l| match l {
+            "5" => "",
+            "10" => "ten",
+            l => l,
+        })
+        .replace("\n\n", "\n");
+        assert_eq!(
+            unified_diff(&old, &new, "x", "x"),
+            "--- x\n+++ x\n@@ -2,12 +2,11 @@\n 2\n 3\n 4\n-5\n 6\n 7\n 8\n 9\n-10\n+ten\n 11\n 12\n 13\n"
+        );
+    }
+}
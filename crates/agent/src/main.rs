@@ -1,30 +1,66 @@
 mod async_job;
 mod config;
+mod conn;
 mod cron;
+mod diff;
 mod manager;
+mod spool;
+mod state;
 mod task;
 mod trigger;
-use bytes::BytesMut;
 use clap::Parser;
 use config::Config;
+use conn::{Credentials, ServerConn};
 use log::LevelFilter;
 use manager::TaskManager;
-use protocol::{make_key, DecodeError, Request, Response, TaskSpec};
-use serde::{de::DeserializeOwned, Serialize};
+use protocol::{
+    make_key, Capabilities, Compression, Connector, FrameCodec, FrameLimits, Hello, Message,
+    PublicKey, Request, RequestId, Response, ResponseError, TaskError, TaskResult, Verdict,
+    Welcome, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use spool::{EventSpool, EventSpoolLocked};
+use state::AgentState;
+use std::collections::HashMap;
 use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::{collections::HashMap, path::PathBuf};
+use std::time::Duration;
 use tokio::{
-    io::{self, AsyncReadExt, AsyncWriteExt, BufWriter},
-    net::TcpStream,
-    sync::Mutex,
+    io,
+    sync::{mpsc, oneshot, Mutex},
+    time::{Instant, MissedTickBehavior},
 };
 use uuid::Uuid;
 
+/// seconds an agent unknown to the server waits before asking again, in case
+/// it was registered meanwhile
+const UNKNOWN_RETRY_AFTER: u64 = 10 * 60;
+
+/// answer to a rollback request of the server
+fn rollback_response(task: &Uuid, result: Result<TaskResult, TaskError>) -> Response {
+    match result {
+        Ok(result) => Response::object(&result),
+        Err(TaskError::TaskNotFound) => {
+            Response::err(ResponseError::NotFound(format!("task {}", task)))
+        }
+        Err(e @ TaskError::NoBackup(_)) => Response::err(ResponseError::NotFound(e.to_string())),
+        Err(e) => Response::err(ResponseError::Internal(e.to_string())),
+    }
+}
+
+/// a request to the server and where to deliver its response
+type SessionCall = (Request, oneshot::Sender<Response>);
+
 struct Agent {
     server: String,
+    connector: Connector,
     agent_id: Uuid,
-    aes_key: [u8; 32],
+    credentials: Credentials,
+    limits: FrameLimits,
+    /// compressions we accept, best first
+    compression: Vec<String>,
+    compression_threshold: usize,
 
     pull: bool,
     pull_interval: u64,
@@ -34,39 +70,89 @@ struct Agent {
     report: bool,
     report_interval: u64,
 
-    tm: Arc<Mutex<TaskManager>>,
+    session: bool,
+    heartbeat_interval: u64,
+    /// whether a session with the server is up
+    connected: AtomicBool,
+    /// requests to send through the session while it is up
+    session_calls: Mutex<Option<mpsc::Sender<SessionCall>>>,
+    /// set while the server tells us it does not know this agent
+    unknown: AtomicBool,
+    /// the server asked us not to send requests before then
+    retry_at: Mutex<Option<Instant>>,
 
-    last_report: chrono::DateTime<chrono::Local>,
+    tm: Arc<Mutex<TaskManager>>,
+    spool: EventSpoolLocked,
 }
 
 impl Agent {
     async fn new(config: Config, task_file: PathBuf) -> Self {
+        let spool_file = config
+            .spool_file
+            .unwrap_or_else(|| task_file.with_file_name("spool.jsonl"));
+        let spool = Arc::new(Mutex::new(
+            EventSpool::new(
+                spool_file,
+                config.spool_max_size,
+                Duration::from_secs(config.spool_max_age),
+            )
+            .await,
+        ));
+        let state_file = config
+            .state_file
+            .unwrap_or_else(|| task_file.with_file_name("state.json"));
+        let state = Arc::new(Mutex::new(AgentState::new(state_file).await));
+
         Self {
+            connector: Connector::new(config.tls.as_ref(), &config.server)
+                .expect("invalid tls config"),
             server: config.server,
             agent_id: config.agent_id,
-            aes_key: make_key(&config.key),
+            credentials: Credentials {
+                agent_id: config.agent_id,
+                secret: protocol::parse_secret_key(&config.private_key)
+                    .expect("invalid private_key in config"),
+                server_public: protocol::parse_public_key(&config.server_public_key)
+                    .expect("invalid server_public_key in config"),
+                psk: make_key(&config.key),
+            },
+            limits: config.limits,
+            compression: config.compression,
+            compression_threshold: config.compression_threshold,
 
             task_file,
-            tm: Arc::new(Mutex::new(TaskManager::new().await)),
+            tm: Arc::new(Mutex::new(TaskManager::new(spool.clone(), state).await)),
+            spool,
 
             pull: config.pull,
             pull_interval: config.pull_interval,
 
             report: config.report,
             report_interval: config.report_interval,
-            last_report: chrono::Local::now(),
+
+            session: config.session,
+            heartbeat_interval: config.heartbeat_interval,
+            connected: AtomicBool::new(false),
+            session_calls: Mutex::new(None),
+            unknown: AtomicBool::new(false),
+            retry_at: Mutex::new(None),
         }
     }
 
     async fn start(self: &Arc<Self>) {
-        if let Ok(specs) = config::load(self.task_file.as_path()) {
-            let mut tm = self.tm.lock().await;
-            if let Err(e) = tm.reload(specs).await {
-                log::error!("load tasks failed: {:?}", e);
-            }
+        if let Err(e) = self.load_tasks().await {
+            log::warn!("load tasks failed: {}", e);
         }
 
-        self.tm.lock().await.start_tick().await;
+        self.tm.lock().await.start_cron().await;
+
+        if self.session {
+            log::info!("start session loop");
+            let me = self.clone();
+            tokio::spawn(async move {
+                me.session_loop().await;
+            });
+        }
 
         if self.pull {
             log::info!("start pull loop");
@@ -86,160 +172,423 @@ impl Agent {
 
         // wait forever
         tokio::signal::ctrl_c().await.unwrap();
+
+        self.tm.lock().await.stop_cron().await;
     }
 
     async fn pull_loop(self: &Arc<Self>) {
-        let mut interval =
-            tokio::time::interval(std::time::Duration::from_secs(self.pull_interval));
+        let mut interval = tokio::time::interval(Duration::from_secs(self.pull_interval));
+        // no burst of ticks after backing off
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
         loop {
-            if let Err(e) = self.pull().await {
-                log::error!("Pull failed: {}", e);
+            self.backoff().await;
+            // tasks are pushed by the server while the session is up
+            if !self.connected.load(Ordering::SeqCst) {
+                if let Err(e) = self.pull().await {
+                    log::error!("Pull failed: {}", e);
+                }
             }
             interval.tick().await;
         }
     }
 
     async fn report_loop(self: &Arc<Self>) {
-        let mut interval =
-            tokio::time::interval(std::time::Duration::from_secs(self.report_interval));
+        let mut interval = tokio::time::interval(Duration::from_secs(self.report_interval));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
         loop {
             interval.tick().await;
+            self.backoff().await;
             if let Err(e) = self.report().await {
                 log::error!("Report failed: {}", e);
             }
         }
     }
 
-    fn encode(&self, t: impl Serialize, buf: &mut BytesMut) -> bool {
-        protocol::encode(t, buf, &self.aes_key)
+    async fn session_loop(self: &Arc<Self>) {
+        loop {
+            self.backoff().await;
+            if let Err(e) = self.session().await {
+                log::error!("Session failed: {}", e);
+            }
+            *self.session_calls.lock().await = None;
+            if self.connected.swap(false, Ordering::SeqCst) && !self.unknown.load(Ordering::SeqCst)
+            {
+                log::warn!("Session lost, fall back to polling");
+            }
+            tokio::time::sleep(Duration::from_secs(self.heartbeat_interval)).await;
+        }
     }
 
-    fn decode<T>(&self, buf: &mut BytesMut) -> Result<T, DecodeError>
-    where
-        T: DeserializeOwned,
-    {
-        protocol::decode(buf, &self.aes_key)
+    fn hello(&self) -> Hello {
+        let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect();
+        Hello {
+            agent_id: self.agent_id,
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            agent_version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: Capabilities {
+                task_types: strings(&["FileUpdate", "Command", "Hosts"]),
+                trigger_types: strings(&["Cron", "Immediate", "Startup"]),
+                compression: self.compression.clone(),
+            },
+        }
     }
 
-    async fn pull(self: &Arc<Self>) -> io::Result<()> {
-        let stream = TcpStream::connect(&self.server).await?;
-        log::debug!("Pull tasks from: {}", self.server);
-
-        stream.set_nodelay(true).expect("Failed to set nodelay");
-
-        let mut wfile = BufWriter::new(stream);
-        let mut buf = BytesMut::new();
-
-        let req = Request::PullTask {
-            id: self.agent_id.clone(),
+    /// connect to the server and do the handshake
+    async fn connect(&self) -> io::Result<ServerConn> {
+        let mut codec = FrameCodec::new(self.limits);
+        // the server sends with the compression we listed first as well
+        let compression = Compression::negotiate(&self.compression);
+        codec.set_compression(compression, self.compression_threshold);
+
+        let mut conn = match ServerConn::connect(
+            &self.connector,
+            &self.server,
+            &self.credentials,
+            codec,
+        )
+        .await
+        {
+            Ok(conn) => conn,
+            // refused by the server before the key exchange
+            Err(e)
+                if e.get_ref().and_then(|e| e.downcast_ref())
+                    == Some(&ResponseError::UnknownAgent) =>
+            {
+                return Err(self.server_error(ResponseError::UnknownAgent).await)
+            }
+            Err(e)
+                if e.get_ref().and_then(|e| e.downcast_ref())
+                    == Some(&Verdict::KeyNotRegistered) =>
+            {
+                if !self.unknown.swap(true, Ordering::SeqCst) {
+                    log::error!(
+                        "Agent [{}] has no public key registered on the server, set public_key {} in its agent db, asking again every {}s",
+                        self.agent_id,
+                        protocol::encode_public_key(&PublicKey::from(&self.credentials.secret)),
+                        UNKNOWN_RETRY_AFTER
+                    );
+                }
+                self.retry_after(UNKNOWN_RETRY_AFTER).await;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
         };
-        if !self.encode(req, &mut buf) {
-            return Err(ErrorKind::InvalidData.into());
+        conn.send(self.hello()).await?;
+        // only the server holding the pinned key is able to answer
+        let welcome: Result<Welcome, String> = conn.recv().await?;
+        match welcome {
+            Ok(welcome) if welcome.protocol_version != conn.version() => {
+                log::error!(
+                    "Security: server welcomed with version {}, greeted with {}",
+                    welcome.protocol_version,
+                    conn.version()
+                );
+                Err(ErrorKind::InvalidData.into())
+            }
+            Ok(welcome) => {
+                log::debug!(
+                    "Connected to server {} with protocol version {}",
+                    welcome.server_version,
+                    welcome.protocol_version
+                );
+                Ok(conn)
+            }
+            Err(e) => {
+                log::error!("Server refused handshake: {}", e);
+                Err(io::Error::new(ErrorKind::Unsupported, e))
+            }
         }
-        wfile.write_all(&buf).await?;
-        wfile.flush().await?;
-
-        buf.clear();
-        'resp: loop {
-            let n = wfile.read_buf(&mut buf).await?;
-            if n == 0 {
-                log::error!("pull connection reset without response");
-                return Err(ErrorKind::ConnectionReset.into());
+    }
+
+    /// send a request and wait for the response, errors answered by the
+    /// server are returned as `Err`
+    async fn request(&self, req: Request) -> io::Result<Response> {
+        match self.send_request(req).await? {
+            Response::Error(e) => Err(self.server_error(e).await),
+            resp => {
+                self.registered();
+                Ok(resp)
             }
+        }
+    }
 
-            while buf.len() > protocol::HEADER_LEN {
-                let msg: Response = match self.decode(&mut buf) {
-                    Ok(msg) => msg,
-                    Err(DecodeError::NotEnoughData) => {
-                        break;
-                    }
-                    Err(DecodeError::InvalidData) => {
-                        log::error!("msg decode failed: invalid data");
-                        return Err(ErrorKind::InvalidData.into());
-                    }
-                };
+    /// the server answered a request, so it knows us (again)
+    fn registered(&self) {
+        if self.unknown.swap(false, Ordering::SeqCst) {
+            log::info!("Agent [{}] is registered on the server", self.agent_id);
+        }
+    }
 
-                match msg {
-                    Response::Object(_) => {},
-                    Response::Error(msg) => {
-                        log::error!("server error: {}", msg);
-                        return Err(ErrorKind::InvalidData.into());
-                    }
-                    _ => {
-                        log::error!("unexpected response: {:?}", msg);
-                        return Err(ErrorKind::InvalidData.into());
-                    }
-                };
+    /// react to an error answered by the server
+    async fn server_error(&self, e: ResponseError) -> io::Error {
+        match &e {
+            ResponseError::UnknownAgent => {
+                if !self.unknown.swap(true, Ordering::SeqCst) {
+                    log::error!(
+                        "Agent [{}] is not registered on the server, asking again every {}s",
+                        self.agent_id,
+                        UNKNOWN_RETRY_AFTER
+                    );
+                }
+                self.retry_after(UNKNOWN_RETRY_AFTER).await;
+            }
+            ResponseError::RateLimited(secs) | ResponseError::Busy(secs) => {
+                log::warn!("Server asked to back off: {}", e);
+                self.retry_after(*secs).await;
+            }
+            ResponseError::Unauthorized(_) => log::error!("Security: server refused: {}", e),
+            _ => log::error!("server error: {}", e),
+        }
+        io::Error::other(e)
+    }
 
-                let msg = match msg.into() {
-                    Ok(msg) => msg,
-                    Err(e) => {
-                        log::error!("msg decode failed: {:?}", e);
-                        return Err(ErrorKind::InvalidData.into());
-                    }
-                };
+    /// send no request for the next `secs` seconds
+    async fn retry_after(&self, secs: u64) {
+        let until = Instant::now() + Duration::from_secs(secs);
+        let mut retry_at = self.retry_at.lock().await;
+        if retry_at.is_none_or(|t| t < until) {
+            *retry_at = Some(until);
+        }
+    }
 
-                config::dump(&msg, "tasks.json").expect("save task failed");
+    /// wait until the server is willing to take requests again
+    async fn backoff(&self) {
+        let retry_at = *self.retry_at.lock().await;
+        if let Some(until) = retry_at {
+            tokio::time::sleep_until(until).await;
+        }
+    }
 
-                if let Err(e) = self.tm.lock().await.reload(msg).await {
-                    log::error!("reload failed, error: {:?}", e);
+    /// send a request through the session, or on a fresh connection while
+    /// there is none, and wait for the response
+    async fn send_request(&self, req: Request) -> io::Result<Response> {
+        let session = self.session_calls.lock().await.clone();
+        let req = match session {
+            Some(calls) => {
+                let (tx, rx) = oneshot::channel();
+                match calls.send((req, tx)).await {
+                    // the session closed before answering
+                    Ok(()) => return rx.await.map_err(|_| ErrorKind::ConnectionReset.into()),
+                    Err(mpsc::error::SendError((req, _))) => req,
                 }
+            }
+            None => req,
+        };
 
-                break 'resp;
+        let mut conn = self.connect().await?;
+        conn.send(Message::Request { id: 0, req }).await?;
+        loop {
+            match conn.recv().await? {
+                Message::Response { id: 0, resp } => return Ok(resp),
+                msg => log::warn!("Unexpected message: {:?}", msg),
             }
         }
+    }
 
-        Ok(())
+    /// keep a connection open to get task changes and requests pushed by the
+    /// server, exchanging heartbeats to detect a dead peer; our own requests
+    /// share it while it is up
+    async fn session(self: &Arc<Self>) -> io::Result<()> {
+        let mut conn = self.connect().await?;
+        let mut next_id: RequestId = 0;
+        // id of the subscription, the server answers it with the task list
+        // and again with an error once the agent was removed
+        let subscription = next_id;
+        conn.send(Message::Request {
+            id: subscription,
+            req: Request::Subscribe { id: self.agent_id },
+        })
+        .await?;
+        log::info!("Session established with: {}", self.server);
+
+        let (calls_tx, mut calls) = mpsc::channel::<SessionCall>(16);
+        *self.session_calls.lock().await = Some(calls_tx);
+        // requests we sent, None for heartbeats
+        let mut pending: HashMap<RequestId, Option<oneshot::Sender<Response>>> = HashMap::new();
+        // responses to the requests of the server handled in the background
+        let (replies_tx, mut replies) = mpsc::unbounded_channel::<(RequestId, Response)>();
+
+        let mut heartbeat = tokio::time::interval(Duration::from_secs(self.heartbeat_interval));
+        let timeout = Duration::from_secs(self.heartbeat_interval * 3);
+        let mut deadline = Instant::now() + timeout;
+        loop {
+            tokio::select! {
+                msg = conn.recv() => {
+                    deadline = Instant::now() + timeout;
+
+                    match msg? {
+                        Message::Response { id, resp } if id == subscription => match resp {
+                            // full task list answering the subscription
+                            Response::Object(_) => {
+                                self.registered();
+                                self.connected.store(true, Ordering::SeqCst);
+                                self.apply_tasks(resp).await?;
+                            }
+                            Response::Error(e) => return Err(self.server_error(e).await),
+                            resp => {
+                                log::error!("unexpected response: {:?}", resp);
+                                return Err(ErrorKind::InvalidData.into());
+                            }
+                        },
+                        Message::Response { id, resp } => match pending.remove(&id) {
+                            Some(Some(tx)) => {
+                                let _ = tx.send(resp);
+                            }
+                            // heartbeat acknowledged
+                            Some(None) => {}
+                            None => log::warn!("Unexpected response: {:?}", resp),
+                        },
+                        // waits for the runs of the task, answered once done
+                        Message::Request { id, req: Request::Rollback { id: task } } => {
+                            let rollback = self.tm.lock().await.rollback(&task);
+                            match rollback {
+                                Ok(rollback) => {
+                                    let replies = replies_tx.clone();
+                                    tokio::spawn(async move {
+                                        let resp = rollback_response(&task, rollback.run().await);
+                                        let _ = replies.send((id, resp));
+                                    });
+                                }
+                                Err(e) => {
+                                    let resp = rollback_response(&task, Err(e));
+                                    conn.send(Message::Response { id, resp }).await?;
+                                }
+                            }
+                        }
+                        // handled in order, as task changes depend on each other
+                        Message::Request { id, req } => {
+                            let resp = self.handle_request(req).await;
+                            conn.send(Message::Response { id, resp }).await?;
+                        }
+                    }
+                }
+                Some((id, resp)) = replies.recv() => {
+                    conn.send(Message::Response { id, resp }).await?;
+                }
+                Some((req, tx)) = calls.recv() => {
+                    next_id += 1;
+                    conn.send(Message::Request { id: next_id, req }).await?;
+                    pending.insert(next_id, Some(tx));
+                }
+                _ = heartbeat.tick() => {
+                    next_id += 1;
+                    let req = Request::Heartbeat { id: self.agent_id };
+                    conn.send(Message::Request { id: next_id, req }).await?;
+                    pending.insert(next_id, None);
+                }
+                _ = tokio::time::sleep_until(deadline) => {
+                    log::warn!("Session heartbeat timeout");
+                    return Err(ErrorKind::TimedOut.into());
+                }
+            }
+        }
     }
 
-    async fn report(self: &Arc<Self>) -> io::Result<()> {
-        let stream = TcpStream::connect(&self.server).await?;
+    async fn pull(self: &Arc<Self>) -> io::Result<()> {
+        log::debug!("Pull tasks from: {}", self.server);
 
-        stream.set_nodelay(true).expect("Failed to set nodelay");
+        let msg = self
+            .request(Request::PullTask { id: self.agent_id })
+            .await?;
+        self.apply_tasks(msg).await
+    }
 
-        let mut wfile = BufWriter::new(stream);
-        let mut buf = BytesMut::new();
+    /// replace the local tasks with those in a server response
+    async fn apply_tasks(&self, msg: Response) -> io::Result<()> {
+        match msg {
+            Response::Object(_) => {}
+            _ => {
+                log::error!("unexpected response: {:?}", msg);
+                return Err(ErrorKind::InvalidData.into());
+            }
+        };
 
-        let req = Request::ReportStatus {
-            id: self.agent_id.clone(),
-            log: self.tm.lock().await.export_log().await,
+        let msg = match msg.into() {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::error!("msg decode failed: {:?}", e);
+                return Err(ErrorKind::InvalidData.into());
+            }
         };
-        if !self.encode(req, &mut buf) {
-            return Err(ErrorKind::InvalidData.into());
+
+        if let Err(e) = config::dump(&msg, &self.task_file) {
+            log::error!("save tasks failed: {}", e);
         }
-        wfile.write_all(&buf).await?;
-        wfile.flush().await?;
-
-        buf.clear();
-        'resp: loop {
-            let n = wfile.read_buf(&mut buf).await?;
-            if n == 0 {
-                log::error!("report connection reset without response");
-                return Err(ErrorKind::ConnectionReset.into());
-            }
 
-            while buf.len() > protocol::HEADER_LEN {
-                let msg: Response = match self.decode(&mut buf) {
-                    Ok(msg) => msg,
-                    Err(DecodeError::NotEnoughData) => {
-                        break;
-                    }
-                    Err(DecodeError::InvalidData) => {
-                        log::error!("msg decode failed: invalid data");
-                        return Err(ErrorKind::InvalidData.into());
-                    }
-                };
+        if let Err(e) = self.tm.lock().await.reload(msg).await {
+            log::error!("reload failed, error: {:?}", e);
+        }
 
-                let Response::Ok = msg else {
-                    log::error!("Report failed");
-                    return Err(ErrorKind::InvalidData.into());
-                };
+        Ok(())
+    }
 
-                break 'resp;
+    /// handle a request pushed by the server in a session
+    async fn handle_request(&self, req: Request) -> Response {
+        match req {
+            Request::AddTask { id, spec } => {
+                let mut tm = self.tm.lock().await;
+                tm.upsert_task(id, spec).await;
+                self.save_tasks(&tm);
+                Response::ok()
+            }
+            Request::RemoveTask { id } => {
+                let mut tm = self.tm.lock().await;
+                if !tm.remove_task(&id).await {
+                    return Response::err(ResponseError::NotFound(format!("task {}", id)));
+                }
+                self.save_tasks(&tm);
+                Response::ok()
+            }
+            Request::ListTask => Response::object(&self.tm.lock().await.status().await),
+            Request::Reload => match self.load_tasks().await {
+                Ok(()) => Response::ok(),
+                Err(e) => Response::err(ResponseError::Internal(e)),
+            },
+            _ => {
+                log::error!("Unhandled request: {:?}", req);
+                Response::err(ResponseError::Unsupported("unhandled request".to_string()))
             }
         }
+    }
 
-        Ok(())
+    /// (re)load tasks from the local task file
+    async fn load_tasks(&self) -> Result<(), String> {
+        let specs = config::load(&self.task_file).map_err(|e| e.to_string())?;
+        self.tm
+            .lock()
+            .await
+            .reload(specs)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    fn save_tasks(&self, tm: &TaskManager) {
+        if let Err(e) = config::dump(&tm.specs(), &self.task_file) {
+            log::error!("save tasks failed: {}", e);
+        }
+    }
+
+    async fn report(self: &Arc<Self>) -> io::Result<()> {
+        // events stay in the spool until the server acknowledged them
+        let (log, seq, dropped) = {
+            let mut spool = self.spool.lock().await;
+            let (log, seq) = spool.read().await?;
+            (log, seq, spool.dropped())
+        };
+
+        let req = Request::ReportStatus {
+            id: self.agent_id,
+            log,
+            dropped,
+        };
+
+        let Response::Ok = self.request(req).await? else {
+            log::error!("Report failed");
+            return Err(ErrorKind::InvalidData.into());
+        };
+
+        self.spool.lock().await.ack(seq, dropped).await
     }
 }
 
@@ -250,12 +599,16 @@ impl Agent {
 #[command(about = "A Centralized Cron-like Task Manager - Agent End", long_about = None)]
 struct Args {
     /// Control server config file
-    #[arg(short, long, value_name = "FILE")]
-    config: PathBuf,
+    #[arg(short, long, value_name = "FILE", required_unless_present = "keygen")]
+    config: Option<PathBuf>,
 
     /// Tasks file
     #[arg(short, long, value_name = "FILE")]
     task_file: Option<PathBuf>,
+
+    /// Print a new x25519 key pair and exit
+    #[arg(long)]
+    keygen: bool,
 }
 
 #[tokio::main]
@@ -264,7 +617,18 @@ async fn main() {
 
     let args = Args::parse();
 
-    let config = config::load(&args.config).expect("load config failed");
+    if args.keygen {
+        let secret = protocol::generate_secret();
+        println!("private_key: {}", protocol::encode_secret_key(&secret));
+        println!(
+            "public_key: {}",
+            protocol::encode_public_key(&PublicKey::from(&secret))
+        );
+        return;
+    }
+
+    let config_path = args.config.expect("config is required");
+    let config = config::load(config_path).expect("load config failed");
 
     let task_file = args
         .task_file
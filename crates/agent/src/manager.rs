@@ -1,6 +1,8 @@
 use crate::cron::CronScheduler;
-use crate::task::Task;
-use protocol::{Event, TaskError, TaskSpec};
+use crate::spool::EventSpoolLocked;
+use crate::state::AgentStateLocked;
+use crate::task::{Rollback, Task};
+use protocol::{AgentTaskStatus, TaskError, TaskSpec};
 use std::{collections::HashMap, sync::Arc};
 use tokio::sync::Mutex;
 use uuid::Uuid;
@@ -9,38 +11,27 @@ pub struct TaskManager {
     cron: Arc<Mutex<CronScheduler>>,
     crond: Option<tokio::task::JoinHandle<()>>,
     tasks: HashMap<Uuid, Task>,
+    spool: EventSpoolLocked,
+    state: AgentStateLocked,
 }
 
 impl TaskManager {
-    pub async fn new() -> Self {
+    pub async fn new(spool: EventSpoolLocked, state: AgentStateLocked) -> Self {
         Self {
             cron: Arc::new(Mutex::new(CronScheduler::new())),
             tasks: HashMap::new(),
             crond: None,
+            spool,
+            state,
         }
     }
 
-    pub async fn export_log(&mut self) -> HashMap<Uuid, Vec<Event>> {
-        let mut res = HashMap::new();
-        for (id, t) in self.tasks.iter_mut() {
-            res.insert(id.clone(), t.export_log().await);
-        }
-        res
-    }
-
-    pub async fn start_tick(&mut self) {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
-        let crond = self.cron.clone();
-        let handle = tokio::spawn(async move {
-            loop {
-                interval.tick().await;
-                crond.lock().await.tick().await;
-            }
-        });
+    pub async fn start_cron(&mut self) {
+        let handle = tokio::spawn(CronScheduler::run(self.cron.clone()));
         self.crond.replace(handle);
     }
 
-    pub async fn stop_tick(&mut self) {
+    pub async fn stop_cron(&mut self) {
         if let Some(handle) = self.crond.take() {
             handle.abort();
             handle.await.unwrap_err();
@@ -54,27 +45,71 @@ impl TaskManager {
         let mut to_remove = Vec::new();
         for (id, _) in self.tasks.iter() {
             if !specs.contains_key(id) {
-                to_remove.push(id.clone());
+                to_remove.push(*id);
             }
         }
         for id in to_remove {
-            self.tasks.remove(&id).unwrap().deactivate().await;
+            self.remove_task(&id).await;
         }
 
         // update or add tasks
         for (id, task_spec) in specs {
-            if let Some(task) = self.tasks.get_mut(&id) {
-                task.update(task_spec).await;
-            } else {
-                self.add_task(id, task_spec).await;
-            }
+            self.upsert_task(id, task_spec).await;
         }
 
         Ok(())
     }
 
+    pub async fn upsert_task(&mut self, id: Uuid, task_spec: TaskSpec) {
+        if let Some(task) = self.tasks.get_mut(&id) {
+            task.update(task_spec).await;
+        } else {
+            self.add_task(id, task_spec).await;
+        }
+    }
+
+    pub async fn remove_task(&mut self, id: &Uuid) -> bool {
+        match self.tasks.remove(id) {
+            Some(mut task) => {
+                task.deactivate().await;
+                self.state.lock().await.remove(id).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn rollback(&self, id: &Uuid) -> Result<Rollback, TaskError> {
+        match self.tasks.get(id) {
+            Some(task) => task.rollback(),
+            None => Err(TaskError::TaskNotFound),
+        }
+    }
+
+    pub fn specs(&self) -> HashMap<Uuid, TaskSpec> {
+        self.tasks
+            .iter()
+            .map(|(id, t)| (*id, t.spec().clone()))
+            .collect()
+    }
+
+    pub async fn status(&self) -> AgentTaskStatus {
+        let mut status = AgentTaskStatus::new();
+        for (id, task) in &self.tasks {
+            status.insert(*id, task.status().await);
+        }
+        status
+    }
+
     pub async fn add_task(&mut self, id: Uuid, task_spec: TaskSpec) {
-        let mut task = Task::new(task_spec, self.cron.clone()).await;
+        let mut task = Task::new(
+            id,
+            task_spec,
+            self.cron.clone(),
+            self.spool.clone(),
+            self.state.clone(),
+        )
+        .await;
         task.try_activate().await;
         self.tasks.insert(id, task);
     }
@@ -0,0 +1,459 @@
+use protocol::{AgentEventLog, Event};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SpoolRecord {
+    seq: u64,
+    task: Uuid,
+    event: Event,
+}
+
+/// a line of the spool file, the dropped count heads the file
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SpoolLine {
+    Record(SpoolRecord),
+    Dropped { dropped: u64 },
+}
+
+#[derive(Serialize)]
+struct DroppedLine {
+    dropped: u64,
+}
+
+/// a pending record and its line in the file
+#[derive(Clone)]
+struct Entry {
+    seq: u64,
+    end: SystemTime,
+    line: Arc<[u8]>,
+}
+
+/// Append-only jsonline file of events waiting to be reported. Records are
+/// only removed once the server acknowledged them, or when they exceed the
+/// size/age cap, in which case they are counted as dropped.
+///
+/// The records are indexed in memory, the file is only read on startup and
+/// all writes run on the blocking thread pool.
+pub struct EventSpool {
+    path: PathBuf,
+    max_size: u64,
+    max_age: Duration,
+    entries: VecDeque<Entry>,
+    /// size of the records in the file
+    size: u64,
+    next_seq: u64,
+    dropped: u64,
+    /// last seq of the report being sent, until it is acknowledged
+    in_flight: Option<u64>,
+    /// events of the report being sent that were compacted away, dropped
+    /// only if that report is not acknowledged
+    unconfirmed: u64,
+}
+
+pub type EventSpoolLocked = Arc<Mutex<EventSpool>>;
+
+/// run blocking file io off the runtime threads
+pub(crate) async fn blocking<T, F>(f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(io::Error::other)?
+}
+
+impl EventSpool {
+    pub async fn new(path: PathBuf, max_size: u64, max_age: Duration) -> Self {
+        let mut spool = Self {
+            path: path.clone(),
+            max_size,
+            max_age,
+            entries: VecDeque::new(),
+            size: 0,
+            next_seq: 0,
+            dropped: 0,
+            in_flight: None,
+            unconfirmed: 0,
+        };
+
+        let loaded = {
+            let path = path.clone();
+            blocking(move || load(&path)).await
+        };
+        match loaded {
+            Ok((records, dropped)) => {
+                for r in records {
+                    match serialize(&r) {
+                        Ok(line) => spool.index(r.seq, r.event.end, line),
+                        Err(e) => log::error!("Failed to index spooled event: {}", e),
+                    }
+                }
+                spool.next_seq = spool.entries.back().map_or(0, |e| e.seq + 1);
+                spool.dropped = dropped;
+                log::info!(
+                    "Event spool loaded, {} pending events, {} dropped",
+                    spool.entries.len(),
+                    dropped
+                );
+            }
+            Err(e) => {
+                // keep the pending events for a look instead of overwriting them
+                let corrupt = path.with_extension("corrupt");
+                log::error!(
+                    "Failed to load event spool, moving it to {}: {}",
+                    corrupt.display(),
+                    e
+                );
+                if let Err(e) = blocking(move || fs::rename(&path, &corrupt)).await {
+                    log::error!("Failed to move event spool: {}", e);
+                }
+                return spool;
+            }
+        }
+        // rewriting also gets rid of a torn record left by a crash
+        if let Err(e) = spool.rewrite().await {
+            log::error!("Failed to rewrite event spool: {}", e);
+        }
+        spool
+    }
+
+    /// number of events dropped since the last acknowledged report
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    fn index(&mut self, seq: u64, end: SystemTime, line: Arc<[u8]>) {
+        self.size += line.len() as u64;
+        self.entries.push_back(Entry { seq, end, line });
+    }
+
+    /// forget the expired events, returning how many
+    fn drop_expired(&mut self) -> usize {
+        let count = self.entries.len();
+        let max_age = self.max_age;
+        self.entries
+            .retain(|e| !e.end.elapsed().is_ok_and(|age| age > max_age));
+        self.size = self.entries.iter().map(|e| e.line.len() as u64).sum();
+        count - self.entries.len()
+    }
+
+    pub async fn append(&mut self, task: Uuid, event: Event) {
+        let record = SpoolRecord {
+            seq: self.next_seq,
+            task,
+            event,
+        };
+        self.next_seq += 1;
+
+        let line = match serialize(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                log::error!("Failed to serialize event: {}", e);
+                self.dropped += 1;
+                return;
+            }
+        };
+
+        // make room for a quarter of the cap at once, so that a full spool
+        // is not rewritten on every append
+        let len = line.len() as u64;
+        if self.size + len > self.max_size {
+            self.compact((self.max_size - self.max_size / 4).saturating_sub(len));
+            if let Err(e) = self.rewrite().await {
+                log::error!("Failed to compact event spool: {}", e);
+            }
+        }
+
+        self.index(record.seq, record.event.end, line.clone());
+        let path = self.path.clone();
+        let res = blocking(move || {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?
+                .write_all(&line)
+        })
+        .await;
+        if let Err(e) = res {
+            log::error!("Failed to spool event: {}", e);
+        }
+    }
+
+    /// collect all pending events, returns them with the seq to acknowledge
+    pub async fn read(&mut self) -> io::Result<(AgentEventLog, Option<u64>)> {
+        // the previous report was not acknowledged
+        self.dropped += std::mem::take(&mut self.unconfirmed);
+        self.in_flight = None;
+
+        let expired = self.drop_expired();
+        if expired > 0 {
+            log::warn!("Dropping {} expired events", expired);
+            self.dropped += expired as u64;
+            self.rewrite().await?;
+        }
+
+        let last_seq = self.entries.back().map(|e| e.seq);
+        self.in_flight = last_seq;
+        let mut log = AgentEventLog::new();
+        for e in &self.entries {
+            let r: SpoolRecord = serde_json::from_slice(&e.line)?;
+            log.entry(r.task).or_default().push(r.event);
+        }
+        Ok((log, last_seq))
+    }
+
+    /// remove all events up to `seq` once the server accepted them, along with
+    /// the `dropped` count that was reported together
+    pub async fn ack(&mut self, seq: Option<u64>, dropped: u64) -> io::Result<()> {
+        self.dropped = self.dropped.saturating_sub(dropped);
+        self.in_flight = None;
+        self.unconfirmed = 0;
+        if let Some(seq) = seq {
+            while self.entries.front().is_some_and(|e| e.seq <= seq) {
+                let e = self.entries.pop_front().expect("front exists");
+                self.size -= e.line.len() as u64;
+            }
+        }
+        self.rewrite().await
+    }
+
+    /// drop expired and then oldest events until they take `target` bytes;
+    /// the events of the report being sent only count as dropped if that
+    /// report fails
+    fn compact(&mut self, target: u64) {
+        let sent = |entries: &VecDeque<Entry>, in_flight| match in_flight {
+            Some(seq) => entries.iter().take_while(|e| e.seq <= seq).count(),
+            None => 0,
+        };
+        let count = self.entries.len();
+        let sent_before = sent(&self.entries, self.in_flight);
+        self.drop_expired();
+        while self.size > target {
+            let Some(e) = self.entries.pop_front() else {
+                break;
+            };
+            self.size -= e.line.len() as u64;
+        }
+
+        let sent_dropped = sent_before - sent(&self.entries, self.in_flight);
+        self.unconfirmed += sent_dropped as u64;
+        let dropped = count - self.entries.len() - sent_dropped;
+        if dropped > 0 {
+            log::warn!("Event spool full, dropping {} events", dropped);
+            self.dropped += dropped as u64;
+        }
+    }
+
+    /// atomically replace the spool file with the dropped count and the
+    /// pending records
+    async fn rewrite(&self) -> io::Result<()> {
+        let path = self.path.clone();
+        let dropped = self.dropped;
+        let lines: Vec<Arc<[u8]>> = self.entries.iter().map(|e| e.line.clone()).collect();
+        blocking(move || {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let tmp = path.with_extension("tmp");
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            serde_json::to_writer(&mut writer, &DroppedLine { dropped })?;
+            writer.write_all(b"\n")?;
+            for line in lines {
+                writer.write_all(&line)?;
+            }
+            writer.into_inner()?.sync_all()?;
+            fs::rename(&tmp, &path)
+        })
+        .await
+    }
+}
+
+/// a record as a line of the spool file
+fn serialize(record: &SpoolRecord) -> io::Result<Arc<[u8]>> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    Ok(line.into())
+}
+
+/// the records of the spool file and its dropped count
+fn load(path: &Path) -> io::Result<(Vec<SpoolRecord>, u64)> {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((vec![], 0)),
+        Err(e) => return Err(e),
+    };
+
+    let mut records = vec![];
+    let mut dropped = 0;
+    for line in content.split(|&b| b == b'\n') {
+        if line.trim_ascii().is_empty() {
+            continue;
+        }
+        match serde_json::from_slice(line) {
+            Ok(SpoolLine::Record(r)) => records.push(r),
+            Ok(SpoolLine::Dropped { dropped: n }) => dropped = n,
+            // a torn write after a crash, skip it
+            Err(e) => log::warn!("Skip corrupted spool record: {}", e),
+        }
+    }
+    Ok((records, dropped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{EventType, TaskResult};
+
+    fn event(end: SystemTime) -> Event {
+        Event {
+            id: Uuid::new_v4(),
+            type_: EventType::Run,
+            start: end,
+            end,
+            attempt: Some(1),
+            parent: None,
+            result: Ok(TaskResult {
+                status: Some(0),
+                ..Default::default()
+            }),
+        }
+    }
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    #[tokio::test]
+    async fn size_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spool.jsonl");
+        let max_size = 4096;
+        let mut spool = EventSpool::new(path.clone(), max_size, HOUR).await;
+        let task = Uuid::new_v4();
+        for _ in 0..100 {
+            spool.append(task, event(SystemTime::now())).await;
+            assert!(spool.size <= max_size);
+        }
+        let kept = spool.entries.len() as u64;
+        assert!(kept > 0);
+        assert_eq!(kept + spool.dropped(), 100);
+        // the oldest are dropped
+        assert_eq!(spool.entries.back().unwrap().seq, 99);
+        assert_eq!(spool.entries.front().unwrap().seq, 100 - kept);
+
+        // the events and the dropped count survive a restart
+        let spool = EventSpool::new(path, max_size, HOUR).await;
+        assert_eq!(spool.entries.len() as u64, kept);
+        assert_eq!(spool.dropped(), 100 - kept);
+        assert_eq!(spool.next_seq, 100);
+    }
+
+    #[tokio::test]
+    async fn age_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spool = EventSpool::new(dir.path().join("spool.jsonl"), 1 << 20, HOUR).await;
+        let task = Uuid::new_v4();
+        spool
+            .append(task, event(SystemTime::now() - 2 * HOUR))
+            .await;
+        spool.append(task, event(SystemTime::now())).await;
+
+        let (log, seq) = spool.read().await.unwrap();
+        assert_eq!(log[&task].len(), 1);
+        assert_eq!(seq, Some(1));
+        assert_eq!(spool.dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn ack() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spool.jsonl");
+        let mut spool = EventSpool::new(path.clone(), 1 << 20, HOUR).await;
+        let task = Uuid::new_v4();
+        spool.dropped = 3;
+        for _ in 0..2 {
+            spool.append(task, event(SystemTime::now())).await;
+        }
+
+        let (_, seq) = spool.read().await.unwrap();
+        let dropped = spool.dropped();
+        // more events come in while the report is sent
+        spool.append(task, event(SystemTime::now())).await;
+        spool.dropped += 1;
+        spool.ack(seq, dropped).await.unwrap();
+
+        let spool = EventSpool::new(path, 1 << 20, HOUR).await;
+        assert_eq!(spool.entries.len(), 1);
+        assert_eq!(spool.entries[0].seq, 2);
+        assert_eq!(spool.dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn compact_in_flight() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spool = EventSpool::new(dir.path().join("spool.jsonl"), 4096, HOUR).await;
+        let task = Uuid::new_v4();
+        for _ in 0..5 {
+            spool.append(task, event(SystemTime::now())).await;
+        }
+        let (_, seq) = spool.read().await.unwrap();
+
+        // the sent events are compacted away while the report is in flight
+        for _ in 0..100 {
+            spool.append(task, event(SystemTime::now())).await;
+        }
+        let kept = spool.entries.len() as u64;
+        assert_eq!(spool.dropped(), 100 - kept);
+        spool.ack(seq, 0).await.unwrap();
+        assert_eq!(spool.dropped(), 100 - kept);
+
+        // unless that report fails
+        let (_, _) = spool.read().await.unwrap();
+        for _ in 0..100 {
+            spool.append(task, event(SystemTime::now())).await;
+        }
+        let (_, _) = spool.read().await.unwrap();
+        assert_eq!(spool.dropped(), 200 - spool.entries.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn bad_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spool.jsonl");
+        let mut spool = EventSpool::new(path.clone(), 1 << 20, HOUR).await;
+        let task = Uuid::new_v4();
+        for _ in 0..2 {
+            spool.append(task, event(SystemTime::now())).await;
+        }
+
+        // a line that is not even utf-8 and a torn one only lose themselves
+        let mut content = std::fs::read(&path).unwrap();
+        content.extend_from_slice(b"\xff\xfe\n{\"seq\": 5, \"ta");
+        std::fs::write(&path, content).unwrap();
+        let spool = EventSpool::new(path, 1 << 20, HOUR).await;
+        assert_eq!(spool.entries.len(), 2);
+        assert_eq!(spool.next_seq, 2);
+    }
+
+    #[tokio::test]
+    async fn unreadable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spool.jsonl");
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join("pending"), "").unwrap();
+
+        // the spool is moved aside rather than overwritten
+        let spool = EventSpool::new(path.clone(), 1 << 20, HOUR).await;
+        assert!(spool.entries.is_empty());
+        assert!(!path.exists());
+        assert!(dir.path().join("spool.corrupt/pending").exists());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::spool::blocking;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// what was last fetched by a FileUpdate task
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileState {
+    /// url the validators below belong to
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// hex sha256 of the content written to the target
+    pub sha256: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateData {
+    #[serde(default)]
+    files: HashMap<Uuid, FileState>,
+    /// last fire time of the cron triggers of each task, by expression
+    #[serde(default)]
+    cron: HashMap<Uuid, HashMap<String, SystemTime>>,
+    /// when the cron triggers of each task were last uninstalled
+    #[serde(default)]
+    deactivated: HashMap<Uuid, SystemTime>,
+}
+
+/// Json file of what the tasks need to remember across runs and restarts,
+/// rewritten on every change on the blocking thread pool.
+pub struct AgentState {
+    path: PathBuf,
+    data: StateData,
+}
+
+pub type AgentStateLocked = Arc<Mutex<AgentState>>;
+
+impl AgentState {
+    pub async fn new(path: PathBuf) -> Self {
+        let read = {
+            let path = path.clone();
+            blocking(move || fs::read(path)).await
+        };
+        let data = match read {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                log::error!("Failed to parse agent state, starting afresh: {}", e);
+                StateData::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => StateData::default(),
+            Err(e) => {
+                log::error!("Failed to load agent state: {}", e);
+                StateData::default()
+            }
+        };
+        Self { path, data }
+    }
+
+    pub fn file(&self, task: &Uuid) -> Option<&FileState> {
+        self.data.files.get(task)
+    }
+
+    pub async fn set_file(&mut self, task: Uuid, state: FileState) {
+        self.data.files.insert(task, state);
+        self.save().await;
+    }
+
+    /// last fire time of a cron trigger, None if the task was deactivated
+    /// since, so that the time it spent deactivated is not caught up
+    pub fn last_fire(&self, task: &Uuid, expr: &str) -> Option<SystemTime> {
+        let last = self.data.cron.get(task)?.get(expr).copied()?;
+        match self.data.deactivated.get(task) {
+            Some(deactivated) if *deactivated >= last => None,
+            _ => Some(last),
+        }
+    }
+
+    /// move the last fire time of a cron trigger forward, runs of several
+    /// fire times may complete out of order
+    pub async fn set_last_fire(&mut self, task: Uuid, expr: &str, time: SystemTime) {
+        let last = self
+            .data
+            .cron
+            .entry(task)
+            .or_default()
+            .entry(expr.to_string())
+            .or_insert(SystemTime::UNIX_EPOCH);
+        if *last < time {
+            *last = time;
+            self.save().await;
+        }
+    }
+
+    /// record that the cron triggers of a task stopped firing
+    pub async fn set_deactivated(&mut self, task: Uuid, time: SystemTime) {
+        self.data.deactivated.insert(task, time);
+        self.save().await;
+    }
+
+    /// forget everything about a removed task
+    pub async fn remove(&mut self, task: &Uuid) {
+        let files = self.data.files.remove(task).is_some();
+        let cron = self.data.cron.remove(task).is_some();
+        let deactivated = self.data.deactivated.remove(task).is_some();
+        if files || cron || deactivated {
+            self.save().await;
+        }
+    }
+
+    async fn save(&self) {
+        if let Err(e) = self.rewrite().await {
+            log::error!("Failed to save agent state: {}", e);
+        }
+    }
+
+    /// atomically replace the state file
+    async fn rewrite(&self) -> io::Result<()> {
+        let path = self.path.clone();
+        let content = serde_json::to_vec(&self.data)?;
+        blocking(move || {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let tmp = path.with_extension("tmp");
+            let mut file = File::create(&tmp)?;
+            file.write_all(&content)?;
+            file.sync_all()?;
+            fs::rename(&tmp, &path)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn last_fire_after_deactivation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let (task, expr) = (Uuid::new_v4(), "0 * * * * *");
+        let t0 = SystemTime::now();
+        let at = |secs| t0 + Duration::from_secs(secs);
+
+        let mut state = AgentState::new(path.clone()).await;
+        state.set_last_fire(task, expr, at(10)).await;
+        // a run of an earlier fire time completing late
+        state.set_last_fire(task, expr, at(5)).await;
+        assert_eq!(state.last_fire(&task, expr), Some(at(10)));
+
+        state.set_deactivated(task, at(20)).await;
+        assert_eq!(state.last_fire(&task, expr), None);
+        state.set_last_fire(task, expr, at(30)).await;
+        assert_eq!(state.last_fire(&task, expr), Some(at(30)));
+
+        // kept across restarts
+        let mut state = AgentState::new(path.clone()).await;
+        assert_eq!(state.last_fire(&task, expr), Some(at(30)));
+        state.set_deactivated(task, at(40)).await;
+        let mut state = AgentState::new(path).await;
+        assert_eq!(state.last_fire(&task, expr), None);
+
+        state.remove(&task).await;
+        state.set_last_fire(task, expr, at(1)).await;
+        assert_eq!(state.last_fire(&task, expr), Some(at(1)));
+    }
+}
@@ -1,38 +1,389 @@
-use crate::async_job::{AsyncTask, CommandTask, FileUpdateTask, HostTask};
+use crate::async_job::{
+    rollback_file, AsyncTask, AsyncTaskTrait, CommandTask, FileUpdateTask, HostTask,
+};
 use crate::cron::CronSchedulerLocked;
+use crate::spool::EventSpoolLocked;
+use crate::state::AgentStateLocked;
 use crate::trigger::{CronTrigger, ImmediateTrigger, StartupTrigger, Trigger};
-use protocol::{Event, TaskError, TaskResult, TaskSpec, TaskType, TriggerSpec};
-use std::collections::VecDeque;
+use protocol::{
+    Action, Change, Concurrency, Event, FileSpec, TaskError, TaskResult, TaskSpec, TaskStatus,
+    TaskType, TriggerSpec,
+};
+use rand::Rng;
 use std::sync::Arc;
-use std::time::SystemTime;
-use tokio::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{oneshot, Mutex, OwnedSemaphorePermit, Semaphore};
 use uuid::Uuid;
 
-pub struct TaskExecContext {
-    pub task: AsyncTask,
-    pub run_history: VecDeque<Event>,
+/// delay before the next attempt after `attempt` failed runs, None if no more retries
+fn retry_delay(on_error: &Action, attempt: u8) -> Option<Duration> {
+    let secs = match *on_error {
+        Action::Retry { times, interval } if attempt <= times => interval,
+        Action::Backoff {
+            times,
+            interval,
+            max_interval,
+        } if attempt <= times => {
+            let factor = 1u64.checked_shl(attempt as u32 - 1).unwrap_or(u64::MAX);
+            interval.saturating_mul(factor).min(max_interval)
+        }
+        Action::Jitter {
+            times,
+            interval,
+            jitter,
+        } if attempt <= times => {
+            let jitter = rand::thread_rng().gen_range(0..=jitter.saturating_mul(1000));
+            return Some(Duration::from_secs(interval) + Duration::from_millis(jitter));
+        }
+        _ => return None,
+    };
+    Some(Duration::from_secs(secs))
 }
 
-impl TaskExecContext {
-    pub async fn run(&mut self) {
+/// max runs of a task at the same time under `concurrency`
+fn run_limit(concurrency: &Concurrency) -> usize {
+    match *concurrency {
+        Concurrency::Allow { max } => max.max(1),
+        _ => 1,
+    }
+}
+
+/// what a run needs, taken from the context when it starts so that the
+/// context is not locked while running
+struct Run {
+    task: AsyncTask,
+    on_error: Action,
+    timeout: Option<u64>,
+    id: Uuid,
+    spool: EventSpoolLocked,
+}
+
+impl Run {
+    /// run the task, retrying failed attempts according to the `on_error` policy
+    async fn run(&self) {
+        let mut attempt = 1;
+        loop {
+            if self.run_once(attempt).await {
+                return;
+            }
+
+            let Some(delay) = retry_delay(&self.on_error, attempt) else {
+                return;
+            };
+
+            log::warn!(
+                "Task run failed at attempt {}, retry in {:?}",
+                attempt,
+                delay
+            );
+            tokio::time::sleep(delay).await;
+            attempt = attempt.saturating_add(1);
+        }
+    }
+
+    /// run the task once and record the event, returning whether it succeeded
+    async fn run_once(&self, attempt: u8) -> bool {
         let start = SystemTime::now();
-        let result = self.task.run().await;
+        let result = match self.timeout {
+            // dropping the run on timeout also kills any spawned process group
+            Some(secs) => tokio::time::timeout(Duration::from_secs(secs), self.task.run())
+                .await
+                .unwrap_or(Err(TaskError::Timeout(secs))),
+            None => self.task.run().await,
+        };
         let end = SystemTime::now();
-        self.run_history.push_back(Event {
+        let succeeded = matches!(
+            result,
+            Ok(TaskResult {
+                status: Some(0),
+                ..
+            })
+        );
+        let changed = matches!(
+            result,
+            Ok(TaskResult {
+                change: Some(Change::Updated),
+                ..
+            })
+        );
+        let event = Event {
             id: Uuid::new_v4(),
             type_: protocol::EventType::Run,
             start,
             end,
+            attempt: Some(attempt),
+            parent: None,
             result,
+        };
+        let parent = event.id;
+        self.spool.lock().await.append(self.id, event).await;
+
+        if succeeded && changed {
+            self.run_hook(parent, "updated").await;
+        }
+        succeeded
+    }
+
+    /// run the `on_change` command of the task after the event `parent`
+    /// changed its target, recording its result linked to that event; a
+    /// failed hook does not fail the run, which is not retried
+    async fn run_hook(&self, parent: Uuid, what: &str) {
+        let Some(command_spec) = self.task.on_change() else {
+            return;
+        };
+        let hook = CommandTask {
+            command_spec: command_spec.clone(),
+        };
+
+        let start = SystemTime::now();
+        let result = hook.run().await.map(|mut result| {
+            let status = result
+                .status
+                .map_or("killed".to_string(), |code| format!("exit {}", code));
+            result.message = format!("{} → hook {}", what, status);
+            result
         });
+        if let Err(e) = &result {
+            log::warn!("on_change hook of task {} failed: {}", self.id, e);
+        }
+        let event = Event {
+            id: Uuid::new_v4(),
+            type_: protocol::EventType::Hook,
+            start,
+            end: SystemTime::now(),
+            attempt: None,
+            parent: Some(parent),
+            result,
+        };
+        self.spool.lock().await.append(self.id, event).await;
     }
+}
+
+/// a run in progress, cancelled through `cancel` to replace it with a newer
+/// one
+struct Running {
+    id: u64,
+    cancel: oneshot::Sender<()>,
+}
+
+/// a run given a slot, registered as running along with it so that a
+/// replacing run never misses it
+struct Admitted {
+    permit: OwnedSemaphorePermit,
+    run: Run,
+    id: u64,
+    cancelled: oneshot::Receiver<()>,
+}
 
-    pub fn export_log(&mut self) -> Vec<Event> {
-        let mut res = vec![];
-        while let Some(run) = self.run_history.pop_front() {
-            res.push(run);
+pub struct TaskExecContext {
+    pub task: AsyncTask,
+    pub on_error: Action,
+    pub timeout: Option<u64>,
+    pub id: Uuid,
+    pub spool: EventSpoolLocked,
+    concurrency: Concurrency,
+    /// one permit per run allowed at the same time
+    slots: Arc<Semaphore>,
+    running: Vec<Running>,
+    /// runs waiting for a slot under `Concurrency::Queue`
+    queued: usize,
+    /// bumped on each change of `concurrency`, so that runs queued under a
+    /// previous policy do not count in the queue of the new one
+    generation: u64,
+    /// ticket of the latest run waiting to replace the running ones under
+    /// `Concurrency::Replace`, the runs waiting with an older one give up
+    replacing: u64,
+    next_run: u64,
+}
+
+impl TaskExecContext {
+    pub fn new(task: AsyncTask, spec: &TaskSpec, id: Uuid, spool: EventSpoolLocked) -> Self {
+        Self {
+            task,
+            on_error: spec.on_error.clone(),
+            timeout: spec.timeout,
+            id,
+            spool,
+            concurrency: spec.concurrency.clone(),
+            slots: Arc::new(Semaphore::new(run_limit(&spec.concurrency))),
+            running: vec![],
+            queued: 0,
+            generation: 0,
+            replacing: 0,
+            next_run: 0,
         }
-        res
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.running.is_empty()
+    }
+
+    /// resize the slots to the new policy, a slot taken away is only freed
+    /// once the run holding it ends
+    pub fn set_concurrency(&mut self, concurrency: &Concurrency) {
+        if self.concurrency == *concurrency {
+            return;
+        }
+        let (old, new) = (run_limit(&self.concurrency), run_limit(concurrency));
+        self.concurrency = concurrency.clone();
+        self.queued = 0;
+        self.generation += 1;
+        if new > old {
+            self.slots.add_permits(new - old);
+        } else if new < old {
+            // waiters are served in order, so no run gets the slots first
+            let slots = self.slots.clone();
+            tokio::spawn(async move {
+                if let Ok(permits) = slots.acquire_many_owned((old - new) as u32).await {
+                    permits.forget();
+                }
+            });
+        }
+    }
+
+    fn snapshot(&self) -> Run {
+        Run {
+            task: self.task.clone(),
+            on_error: self.on_error.clone(),
+            timeout: self.timeout,
+            id: self.id,
+            spool: self.spool.clone(),
+        }
+    }
+
+    async fn skip(&self, reason: &str) {
+        log::warn!("Task {} skipped: {}", self.id, reason);
+        let now = SystemTime::now();
+        let event = Event {
+            id: Uuid::new_v4(),
+            type_: protocol::EventType::Skipped,
+            start: now,
+            end: now,
+            attempt: None,
+            parent: None,
+            result: Ok(TaskResult {
+                message: reason.to_string(),
+                ..Default::default()
+            }),
+        };
+        self.spool.lock().await.append(self.id, event).await;
+    }
+
+    fn admitted(&mut self, permit: OwnedSemaphorePermit) -> Admitted {
+        let (cancel, cancelled) = oneshot::channel();
+        let id = self.next_run;
+        self.next_run += 1;
+        self.running.push(Running { id, cancel });
+        Admitted {
+            permit,
+            run: self.snapshot(),
+            id,
+            cancelled,
+        }
+    }
+
+    /// record that a run was cancelled in favour of a newer one
+    async fn replaced(&self) {
+        let now = SystemTime::now();
+        let event = Event {
+            id: Uuid::new_v4(),
+            type_: protocol::EventType::Run,
+            start: now,
+            end: now,
+            attempt: None,
+            parent: None,
+            result: Err(TaskError::Replaced),
+        };
+        self.spool.lock().await.append(self.id, event).await;
+    }
+
+    /// wait for a slot to run in according to the `concurrency` policy, None
+    /// if the run is skipped or replaced while waiting
+    async fn admit(ctx: &TaskExecContextLocked) -> Option<Admitted> {
+        let mut guard = ctx.lock().await;
+        let slots = guard.slots.clone();
+        if let Ok(permit) = slots.clone().try_acquire_owned() {
+            return Some(guard.admitted(permit));
+        }
+
+        let mut queued = None;
+        let mut replacing = None;
+        match guard.concurrency {
+            Concurrency::Forbid | Concurrency::Allow { .. } => {
+                guard.skip("already running").await;
+                return None;
+            }
+            Concurrency::Queue { max } if guard.queued >= max => {
+                guard.skip("run queue is full").await;
+                return None;
+            }
+            Concurrency::Queue { .. } => {
+                guard.queued += 1;
+                queued = Some(guard.generation);
+            }
+            Concurrency::Replace => {
+                for running in guard.running.drain(..) {
+                    let _ = running.cancel.send(());
+                }
+                guard.replacing += 1;
+                replacing = Some(guard.replacing);
+            }
+        }
+        drop(guard);
+
+        // the semaphore is never closed
+        let permit = slots.acquire_owned().await.ok()?;
+        let mut guard = ctx.lock().await;
+        if queued == Some(guard.generation) {
+            guard.queued -= 1;
+        }
+        if replacing.is_some_and(|ticket| ticket != guard.replacing) {
+            // the slot goes on to the newer run
+            drop(permit);
+            guard.replaced().await;
+            return None;
+        }
+        Some(guard.admitted(permit))
+    }
+
+    /// run the task once a slot is free, retrying failed attempts according
+    /// to the `on_error` policy; a run holds its slot until its last attempt
+    pub async fn run(ctx: &TaskExecContextLocked) {
+        let Some(Admitted {
+            permit,
+            run,
+            id,
+            cancelled,
+        }) = Self::admit(ctx).await
+        else {
+            return;
+        };
+
+        // dropping a replaced run also kills any spawned process group
+        let replaced = tokio::select! {
+            _ = run.run() => false,
+            _ = cancelled => true,
+        };
+        drop(permit);
+
+        let mut guard = ctx.lock().await;
+        guard.running.retain(|r| r.id != id);
+        if replaced {
+            guard.replaced().await;
+        }
+    }
+
+    /// wait until no run is in progress and keep others from starting
+    async fn exclusive(ctx: &TaskExecContextLocked) -> (OwnedSemaphorePermit, Run) {
+        let (slots, limit) = {
+            let guard = ctx.lock().await;
+            (guard.slots.clone(), run_limit(&guard.concurrency))
+        };
+        let permit = slots
+            .acquire_many_owned(limit as u32)
+            .await
+            .expect("semaphore is never closed");
+        (permit, ctx.lock().await.snapshot())
     }
 }
 
@@ -44,36 +395,57 @@ pub enum TaskState {
 }
 
 pub struct Task {
+    id: Uuid,
     spec: TaskSpec,
     context: TaskExecContextLocked,
     triggers: Vec<Trigger>,
     sched: CronSchedulerLocked,
     state: TaskState,
+    spool: EventSpoolLocked,
+    agent_state: AgentStateLocked,
 }
 
 impl Task {
-    pub async fn new(spec: TaskSpec, sched: CronSchedulerLocked) -> Self {
+    pub async fn new(
+        id: Uuid,
+        spec: TaskSpec,
+        sched: CronSchedulerLocked,
+        spool: EventSpoolLocked,
+        agent_state: AgentStateLocked,
+    ) -> Self {
         let mut triggers: Vec<Trigger> = vec![];
         for trig in &spec.triggers {
-            let trigger = Self::make_trigger(&sched, trig).await;
+            let trigger = Self::make_trigger(&sched, trig, id, &agent_state).await;
             triggers.push(trigger);
         }
 
         Self {
-            context: Arc::new(Mutex::new(TaskExecContext {
-                task: Self::make_task(&spec.task).await,
-                run_history: VecDeque::new(),
-            })),
+            context: Arc::new(Mutex::new(TaskExecContext::new(
+                Self::make_task(id, &spec.task, &agent_state).await,
+                &spec,
+                id,
+                spool.clone(),
+            ))),
+            id,
             triggers,
             sched,
             spec,
             state: TaskState::Deactivated,
+            spool,
+            agent_state,
         }
     }
 
-    pub async fn export_log(&mut self) -> Vec<Event> {
-        let mut ctx = self.context.lock().await;
-        ctx.export_log()
+    pub fn spec(&self) -> &TaskSpec {
+        &self.spec
+    }
+
+    pub async fn status(&self) -> TaskStatus {
+        TaskStatus {
+            spec: self.spec.clone(),
+            activated: self.is_activated(),
+            running: self.context.lock().await.is_running(),
+        }
     }
 
     pub fn is_activated(&self) -> bool {
@@ -86,7 +458,7 @@ impl Task {
     pub async fn update(&mut self, spec: TaskSpec) {
         // if the task type has changed, we need to recreate the task
         if self.spec.task != spec.task {
-            let task = Self::make_task(&spec.task).await;
+            let task = Self::make_task(self.id, &spec.task, &self.agent_state).await;
 
             self.deactivate().await;
             self.context.lock().await.task = task;
@@ -97,7 +469,8 @@ impl Task {
             // diff the triggers
             let mut new_triggers: Vec<Trigger> = vec![];
             for trig in &spec.triggers {
-                let trigger = Self::make_trigger(&self.sched, trig).await;
+                let trigger =
+                    Self::make_trigger(&self.sched, trig, self.id, &self.agent_state).await;
                 new_triggers.push(trigger);
             }
 
@@ -105,6 +478,16 @@ impl Task {
             self.triggers = new_triggers;
         }
 
+        if self.spec.on_error != spec.on_error
+            || self.spec.timeout != spec.timeout
+            || self.spec.concurrency != spec.concurrency
+        {
+            let mut ctx = self.context.lock().await;
+            ctx.on_error = spec.on_error.clone();
+            ctx.timeout = spec.timeout;
+            ctx.set_concurrency(&spec.concurrency);
+        }
+
         // update the spec
         self.spec = spec;
 
@@ -112,25 +495,32 @@ impl Task {
         self.try_activate().await;
     }
 
-    async fn make_task(task: &TaskType) -> AsyncTask {
+    async fn make_task(id: Uuid, task: &TaskType, agent_state: &AgentStateLocked) -> AsyncTask {
         match task {
-            TaskType::FileUpdate(spec) => Box::new(FileUpdateTask {
+            TaskType::FileUpdate(spec) => Arc::new(FileUpdateTask {
                 file_spec: spec.clone(),
+                id,
+                state: agent_state.clone(),
             }),
-            TaskType::Command(spec) => Box::new(CommandTask {
+            TaskType::Command(spec) => Arc::new(CommandTask {
                 command_spec: spec.clone(),
             }),
-            TaskType::Hosts(spec) => Box::new(HostTask {
+            TaskType::Hosts(spec) => Arc::new(HostTask {
                 host_spec: spec.clone(),
             }),
         }
     }
 
-    async fn make_trigger(sched: &CronSchedulerLocked, trigger: &TriggerSpec) -> Trigger {
+    async fn make_trigger(
+        sched: &CronSchedulerLocked,
+        trigger: &TriggerSpec,
+        id: Uuid,
+        agent_state: &AgentStateLocked,
+    ) -> Trigger {
         match trigger {
-            TriggerSpec::Cron(expr) => {
-                Box::new(CronTrigger::new(sched.clone(), expr.to_string()).await)
-            }
+            TriggerSpec::Cron(spec) => Box::new(
+                CronTrigger::new(sched.clone(), spec.clone(), id, agent_state.clone()).await,
+            ),
             TriggerSpec::Immediate => Box::new(ImmediateTrigger::new()),
             TriggerSpec::Startup => Box::new(StartupTrigger::new()),
         }
@@ -149,16 +539,16 @@ impl Task {
                 type_: protocol::EventType::TriggerInstall,
                 start,
                 end: SystemTime::now(),
+                attempt: None,
+                parent: None,
                 result: result.clone().map(|_| TaskResult {
                     status: Some(0),
-                    message: "".to_string(),
+                    ..Default::default()
                 }),
             };
-            self.context.lock().await.run_history.push_back(event);
+            self.spool.lock().await.append(self.id, event).await;
 
-            if let Err(_) = result {
-                return result;
-            }
+            result?;
         }
         self.state = TaskState::Activated;
         Ok(())
@@ -176,19 +566,429 @@ impl Task {
                 type_: protocol::EventType::TriggerInstall,
                 start,
                 end: SystemTime::now(),
+                attempt: None,
+                parent: None,
                 result: Ok(TaskResult {
                     status: Some(0),
-                    message: "".to_string(),
+                    ..Default::default()
                 }),
             };
-            self.context.lock().await.run_history.push_back(event);
+            self.spool.lock().await.append(self.id, event).await;
         }
         self.state = TaskState::Deactivated;
     }
 
+    /// the rollback of a FileUpdate task, to run without holding the task
+    pub fn rollback(&self) -> Result<Rollback, TaskError> {
+        let TaskType::FileUpdate(spec) = &self.spec.task else {
+            return Err(TaskError::NoBackup(format!(
+                "{} is not a FileUpdate task",
+                self.spec.name
+            )));
+        };
+        Ok(Rollback {
+            id: self.id,
+            spec: spec.clone(),
+            context: self.context.clone(),
+            spool: self.spool.clone(),
+        })
+    }
+
     pub async fn try_activate(&mut self) {
         if let Err(err) = self.activate().await {
             log::warn!("Failed to activate task: {}", err);
         }
     }
 }
+
+/// restores the previous version of the file of a FileUpdate task, until its
+/// next run fetches the content again
+pub struct Rollback {
+    id: Uuid,
+    spec: FileSpec,
+    context: TaskExecContextLocked,
+    spool: EventSpoolLocked,
+}
+
+impl Rollback {
+    /// wait for the runs in progress, which may take as long as their retries
+    pub async fn run(self) -> Result<TaskResult, TaskError> {
+        // do not race a run
+        let (_permit, run) = TaskExecContext::exclusive(&self.context).await;
+        let start = SystemTime::now();
+        let result = rollback_file(&self.spec).await;
+        let event = Event {
+            id: Uuid::new_v4(),
+            type_: protocol::EventType::Rollback,
+            start,
+            end: SystemTime::now(),
+            attempt: None,
+            parent: None,
+            result: result.clone(),
+        };
+        let parent = event.id;
+        self.spool.lock().await.append(self.id, event).await;
+
+        if result.is_ok() {
+            run.run_hook(parent, "rolled back").await;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spool::EventSpool;
+    use async_trait::async_trait;
+    use protocol::EventType;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// a task whose runs wait until released
+    struct Gate {
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+        release: Semaphore,
+    }
+
+    /// counts a run until it ends, or is dropped when replaced
+    struct Counted<'a>(&'a AtomicUsize);
+
+    impl Drop for Counted<'_> {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    #[async_trait]
+    impl AsyncTaskTrait for Gate {
+        async fn run(&self) -> crate::async_job::AsyncTaskResult {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            let _counted = Counted(&self.running);
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            self.release.acquire().await.unwrap().forget();
+            Ok(TaskResult {
+                status: Some(0),
+                ..Default::default()
+            })
+        }
+    }
+
+    struct Fixture {
+        ctx: TaskExecContextLocked,
+        gate: Arc<Gate>,
+        spool: EventSpoolLocked,
+        _dir: tempfile::TempDir,
+    }
+
+    impl Fixture {
+        async fn new(concurrency: Concurrency) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let spool = EventSpool::new(
+                dir.path().join("spool.jsonl"),
+                1 << 20,
+                Duration::from_secs(3600),
+            )
+            .await;
+            let spool = Arc::new(Mutex::new(spool));
+            let gate = Arc::new(Gate {
+                running: AtomicUsize::new(0),
+                max_running: AtomicUsize::new(0),
+                release: Semaphore::new(0),
+            });
+            let ctx = TaskExecContext {
+                task: gate.clone(),
+                on_error: Action::Ignore,
+                timeout: None,
+                id: Uuid::new_v4(),
+                spool: spool.clone(),
+                slots: Arc::new(Semaphore::new(run_limit(&concurrency))),
+                concurrency,
+                running: vec![],
+                queued: 0,
+                generation: 0,
+                replacing: 0,
+                next_run: 0,
+            };
+            Self {
+                ctx: Arc::new(Mutex::new(ctx)),
+                gate,
+                spool,
+                _dir: dir,
+            }
+        }
+
+        fn start(&self) -> tokio::task::JoinHandle<()> {
+            let ctx = self.ctx.clone();
+            tokio::spawn(async move { TaskExecContext::run(&ctx).await })
+        }
+
+        fn running(&self) -> usize {
+            self.gate.running.load(Ordering::SeqCst)
+        }
+
+        fn release(&self, runs: usize) {
+            self.gate.release.add_permits(runs);
+        }
+
+        async fn set_concurrency(&self, concurrency: Concurrency) {
+            self.ctx.lock().await.set_concurrency(&concurrency);
+        }
+
+        /// number of recorded events matching `f`
+        async fn events(&self, f: impl Fn(&Event) -> bool) -> usize {
+            let (log, _) = self.spool.lock().await.read().await.unwrap();
+            log.values().flatten().filter(|e| f(e)).count()
+        }
+
+        async fn skipped(&self) -> usize {
+            self.events(|e| matches!(e.type_, EventType::Skipped)).await
+        }
+    }
+
+    /// wait for the spawned runs to get where `f` expects them
+    async fn until(f: impl Fn() -> bool) {
+        for _ in 0..400 {
+            if f() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("timed out");
+    }
+
+    #[test]
+    fn retry_delays() {
+        let secs = |on_error: &Action, attempt| retry_delay(on_error, attempt).map(|d| d.as_secs());
+
+        let retry = Action::Retry {
+            times: 2,
+            interval: 5,
+        };
+        assert_eq!(secs(&retry, 1), Some(5));
+        assert_eq!(secs(&retry, 2), Some(5));
+        assert_eq!(secs(&retry, 3), None);
+        assert_eq!(secs(&Action::Ignore, 1), None);
+
+        let backoff = Action::Backoff {
+            times: u8::MAX,
+            interval: 3,
+            max_interval: 3600,
+        };
+        let delays: Vec<_> = (1..=5).map(|a| secs(&backoff, a).unwrap()).collect();
+        assert_eq!(delays, [3, 6, 12, 24, 48]);
+        // the doubling saturates instead of overflowing
+        for attempt in [12, 63, 64, 65, u8::MAX] {
+            assert_eq!(secs(&backoff, attempt), Some(3600), "{}", attempt);
+        }
+        let huge = Action::Backoff {
+            times: 100,
+            interval: u64::MAX / 2,
+            max_interval: u64::MAX,
+        };
+        assert_eq!(secs(&huge, 3), Some(u64::MAX));
+    }
+
+    #[test]
+    fn jitter_bounds() {
+        let jitter = Action::Jitter {
+            times: 3,
+            interval: 10,
+            jitter: 2,
+        };
+        for _ in 0..200 {
+            let delay = retry_delay(&jitter, 1).unwrap();
+            assert!(delay >= Duration::from_secs(10) && delay <= Duration::from_secs(12));
+        }
+        assert_eq!(retry_delay(&jitter, 4), None);
+    }
+
+    /// a task whose runs all exit with status 1
+    struct Failing(AtomicUsize);
+
+    #[async_trait]
+    impl AsyncTaskTrait for Failing {
+        async fn run(&self) -> crate::async_job::AsyncTaskResult {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(TaskResult {
+                status: Some(1),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn attempts() {
+        let f = Fixture::new(Concurrency::Forbid).await;
+        let task = Arc::new(Failing(AtomicUsize::new(0)));
+        let run = Run {
+            task: task.clone(),
+            on_error: Action::Retry {
+                times: 2,
+                interval: 0,
+            },
+            timeout: None,
+            id: Uuid::new_v4(),
+            spool: f.spool.clone(),
+        };
+        run.run().await;
+
+        assert_eq!(task.0.load(Ordering::SeqCst), 3);
+        let (log, _) = f.spool.lock().await.read().await.unwrap();
+        let attempts: Vec<_> = log.values().flatten().map(|e| e.attempt).collect();
+        assert_eq!(attempts, [Some(1), Some(2), Some(3)]);
+    }
+
+    #[tokio::test]
+    async fn forbid() {
+        let f = Fixture::new(Concurrency::Forbid).await;
+        let run = f.start();
+        until(|| f.running() == 1).await;
+        f.start().await.unwrap();
+        assert_eq!(f.skipped().await, 1);
+        f.release(1);
+        run.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn allow() {
+        let f = Fixture::new(Concurrency::Allow { max: 2 }).await;
+        let runs = [f.start(), f.start()];
+        until(|| f.running() == 2).await;
+        f.start().await.unwrap();
+        assert_eq!(f.skipped().await, 1);
+        f.release(2);
+        for run in runs {
+            run.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn queue() {
+        let f = Fixture::new(Concurrency::Queue { max: 1 }).await;
+        let first = f.start();
+        until(|| f.running() == 1).await;
+        let queued = f.start();
+        until(|| f.ctx.try_lock().is_ok_and(|ctx| ctx.queued == 1)).await;
+        f.start().await.unwrap();
+        assert_eq!(f.skipped().await, 1);
+
+        f.release(1);
+        first.await.unwrap();
+        // the queued run starts once the first one is done
+        until(|| f.running() == 1).await;
+        f.release(1);
+        queued.await.unwrap();
+        assert_eq!(f.gate.max_running.load(Ordering::SeqCst), 1);
+        assert_eq!(f.ctx.lock().await.queued, 0);
+    }
+
+    #[tokio::test]
+    async fn replace() {
+        let f = Fixture::new(Concurrency::Replace).await;
+        let first = f.start();
+        until(|| f.running() == 1).await;
+        let second = f.start();
+        first.await.unwrap();
+        until(|| f.running() == 1).await;
+        let replaced = |e: &Event| matches!(e.result, Err(TaskError::Replaced));
+        assert_eq!(f.events(replaced).await, 1);
+
+        f.release(1);
+        second.await.unwrap();
+        assert_eq!(f.events(replaced).await, 1);
+        assert_eq!(f.skipped().await, 0);
+    }
+
+    #[tokio::test]
+    async fn replace_waiting() {
+        let f = Fixture::new(Concurrency::Replace).await;
+        // a rollback holds the slot while the triggers come in
+        let (slot, _) = TaskExecContext::exclusive(&f.ctx).await;
+        let ctx = &f.ctx;
+        let waiting = |n| until(move || ctx.try_lock().is_ok_and(|ctx| ctx.replacing == n));
+        let first = f.start();
+        waiting(1).await;
+        let second = f.start();
+        waiting(2).await;
+        let third = f.start();
+        waiting(3).await;
+        drop(slot);
+
+        // only the latest runs, the others give up their turn without running
+        until(|| first.is_finished() && second.is_finished()).await;
+        until(|| f.running() == 1).await;
+        let replaced = |e: &Event| matches!(e.result, Err(TaskError::Replaced));
+        assert_eq!(f.events(replaced).await, 2);
+
+        f.release(1);
+        third.await.unwrap();
+        assert_eq!(f.gate.max_running.load(Ordering::SeqCst), 1);
+        let runs = |e: &Event| e.attempt.is_some();
+        assert_eq!(f.events(runs).await, 1);
+    }
+
+    #[tokio::test]
+    async fn shrink_waits_for_running() {
+        let f = Fixture::new(Concurrency::Allow { max: 2 }).await;
+        let runs = [f.start(), f.start()];
+        until(|| f.running() == 2).await;
+
+        f.set_concurrency(Concurrency::Forbid).await;
+        f.release(1);
+        until(|| f.running() == 1).await;
+        // the slot of the finished run was taken away
+        f.start().await.unwrap();
+        assert_eq!(f.skipped().await, 1);
+
+        f.release(1);
+        for run in runs {
+            run.await.unwrap();
+        }
+        let run = f.start();
+        until(|| f.running() == 1).await;
+        f.start().await.unwrap();
+        assert_eq!(f.skipped().await, 2);
+        f.release(1);
+        run.await.unwrap();
+        assert_eq!(f.gate.max_running.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn grow_admits_at_once() {
+        let f = Fixture::new(Concurrency::Forbid).await;
+        let first = f.start();
+        until(|| f.running() == 1).await;
+
+        f.set_concurrency(Concurrency::Allow { max: 2 }).await;
+        let second = f.start();
+        until(|| f.running() == 2).await;
+        f.release(2);
+        first.await.unwrap();
+        second.await.unwrap();
+        assert_eq!(f.skipped().await, 0);
+    }
+
+    #[tokio::test]
+    async fn queue_reset_on_change() {
+        let f = Fixture::new(Concurrency::Queue { max: 1 }).await;
+        let first = f.start();
+        until(|| f.running() == 1).await;
+        let queued = f.start();
+        until(|| f.ctx.try_lock().is_ok_and(|ctx| ctx.queued == 1)).await;
+
+        f.set_concurrency(Concurrency::Replace).await;
+        f.set_concurrency(Concurrency::Queue { max: 1 }).await;
+        // the run queued before does not fill the new queue
+        let requeued = f.start();
+        until(|| f.ctx.try_lock().is_ok_and(|ctx| ctx.queued == 1)).await;
+        assert_eq!(f.skipped().await, 0);
+
+        f.release(3);
+        for run in [first, queued, requeued] {
+            run.await.unwrap();
+        }
+        assert_eq!(f.ctx.lock().await.queued, 0);
+        assert_eq!(f.gate.max_running.load(Ordering::SeqCst), 1);
+    }
+}
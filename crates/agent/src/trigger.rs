@@ -1,8 +1,11 @@
 use crate::cron::{CronScheduler, ScheduledJob};
-use crate::task::TaskExecContextLocked;
+use crate::state::AgentStateLocked;
+use crate::task::{TaskExecContext, TaskExecContextLocked};
 use async_trait::async_trait;
-use protocol::{TaskSpecError, TaskError};
+use chrono::{DateTime, Local};
+use protocol::{CronSpec, TaskError, TaskSpecError};
 use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
@@ -14,17 +17,29 @@ pub trait TriggerTrait {
     async fn uninstall(&mut self);
 }
 
+/// Runs the task on a cron schedule; the last fire time is kept in the agent
+/// state, so that the runs missed while the agent was down are caught up as
+/// the spec says. Those missed while the task was deactivated are not.
 pub struct CronTrigger {
     crond: Arc<Mutex<CronScheduler>>,
-    cron_expr: String,
+    spec: CronSpec,
+    task_id: Uuid,
+    state: AgentStateLocked,
     job_id: Option<Uuid>,
 }
 
 impl CronTrigger {
-    pub async fn new(crond: Arc<Mutex<CronScheduler>>, cron_expr: String) -> Self {
+    pub async fn new(
+        crond: Arc<Mutex<CronScheduler>>,
+        spec: CronSpec,
+        task_id: Uuid,
+        state: AgentStateLocked,
+    ) -> Self {
         Self {
             crond,
-            cron_expr,
+            spec,
+            task_id,
+            state,
             job_id: None,
         }
     }
@@ -33,15 +48,38 @@ impl CronTrigger {
 #[async_trait]
 impl TriggerTrait for CronTrigger {
     async fn install(&mut self, ctx: TaskExecContextLocked) -> Result<(), TaskError> {
-        let sched = match self.cron_expr.parse() {
+        let sched = match self.spec.expr.parse() {
             Ok(v) => v,
             Err(_) => return Err(TaskSpecError::InvalidCronExpresion.into()),
         };
 
-        let sched_job = ScheduledJob::from(sched, move || {
+        let last = self
+            .state
+            .lock()
+            .await
+            .last_fire(&self.task_id, &self.spec.expr)
+            .map(DateTime::<Local>::from);
+        let (state, task_id, expr) = (self.state.clone(), self.task_id, self.spec.expr.clone());
+        let sched_job = ScheduledJob::from(sched, last, self.spec.catch_up, move |latest, runs| {
             let ctx = ctx.clone();
+            let state = state.clone();
+            let expr = expr.clone();
             Box::pin(async move {
-                ctx.lock().await.run().await;
+                // advanced as the runs complete, so that those cut short by a
+                // restart are caught up
+                for time in runs {
+                    TaskExecContext::run(&ctx).await;
+                    state
+                        .lock()
+                        .await
+                        .set_last_fire(task_id, &expr, time.into())
+                        .await;
+                }
+                state
+                    .lock()
+                    .await
+                    .set_last_fire(task_id, &expr, latest.into())
+                    .await;
             })
         });
         self.job_id = Some(sched_job.id());
@@ -52,6 +90,11 @@ impl TriggerTrait for CronTrigger {
     async fn uninstall(&mut self) {
         if let Some(uuid) = self.job_id.take() {
             self.crond.lock().await.remove(uuid);
+            self.state
+                .lock()
+                .await
+                .set_deactivated(self.task_id, SystemTime::now())
+                .await;
         }
     }
 }
@@ -68,7 +111,7 @@ impl ImmediateTrigger {
 impl TriggerTrait for ImmediateTrigger {
     async fn install(&mut self, ctx: TaskExecContextLocked) -> Result<(), TaskError> {
         tokio::spawn(async move {
-            ctx.lock().await.run().await;
+            TaskExecContext::run(&ctx).await;
         });
         Ok(())
     }
@@ -88,7 +131,7 @@ impl StartupTrigger {
 impl TriggerTrait for StartupTrigger {
     async fn install(&mut self, ctx: TaskExecContextLocked) -> Result<(), TaskError> {
         tokio::spawn(async move {
-            ctx.lock().await.run().await;
+            TaskExecContext::run(&ctx).await;
         });
         Ok(())
     }
@@ -0,0 +1,86 @@
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use protocol::{
+    AgentEventLog, Compression, Event, EventType, FrameCodec, FrameLimits, Request, SessionKeys,
+    TaskResult, COMPRESSIONS,
+};
+use std::time::SystemTime;
+use tokio_util::codec::{Decoder, Encoder};
+use uuid::Uuid;
+
+/// a report of `tasks` tasks with `events` runs each, with some output
+fn report(tasks: usize, events: usize) -> Request {
+    let mut log = AgentEventLog::new();
+    for _ in 0..tasks {
+        let runs = (0..events)
+            .map(|i| Event {
+                id: Uuid::new_v4(),
+                type_: EventType::Run,
+                start: SystemTime::now(),
+                end: SystemTime::now(),
+                attempt: Some(1),
+                parent: None,
+                result: Ok(TaskResult {
+                    status: Some(0),
+                    message: "exit status: 0".to_string(),
+                    change: None,
+                    stdout: format!("run {}: synced 42 files, 0 errors\n", i).repeat(8),
+                    stderr: String::new(),
+                }),
+            })
+            .collect();
+        log.insert(Uuid::new_v4(), runs);
+    }
+    Request::ReportStatus {
+        id: Uuid::new_v4(),
+        log,
+        dropped: 0,
+    }
+}
+
+/// a codec able to decode its own frames
+fn codec(compression: Compression) -> FrameCodec {
+    let mut codec = FrameCodec::new(FrameLimits::default());
+    let keys = SessionKeys {
+        tx: [7; 32],
+        rx: [7; 32],
+    };
+    codec.set_keys(vec![keys]);
+    codec.set_compression(compression, 0);
+    codec
+}
+
+fn bench(c: &mut Criterion) {
+    for (name, tasks, events) in [("empty", 0, 0), ("small", 4, 4), ("large", 32, 64)] {
+        let msg = report(tasks, events);
+        let size = serde_json::to_vec(&msg).unwrap().len() as u64;
+
+        let mut group = c.benchmark_group(format!("report/{}", name));
+        group.throughput(Throughput::Bytes(size));
+        for compression in COMPRESSIONS {
+            group.bench_function(format!("encode/{}", compression.name()), |b| {
+                let mut codec = codec(compression);
+                b.iter(|| {
+                    let mut buf = BytesMut::new();
+                    codec.encode(&msg, &mut buf).unwrap();
+                    buf
+                })
+            });
+
+            let mut frame = BytesMut::new();
+            codec(compression).encode(&msg, &mut frame).unwrap();
+            // a fresh codec each time, as the frame would be a replay otherwise
+            group.bench_function(format!("decode/{}", compression.name()), |b| {
+                b.iter_batched(
+                    || (codec(compression), frame.clone()),
+                    |(mut codec, mut buf)| codec.decode(&mut buf).unwrap().unwrap(),
+                    BatchSize::SmallInput,
+                )
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
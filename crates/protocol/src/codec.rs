@@ -0,0 +1,464 @@
+use crate::{now_millis, Compression, Key, ReplayWindow, SessionKeys};
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use bytes::{Buf, BufMut, BytesMut};
+use rand::Rng;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, ErrorKind};
+use tokio_util::codec::{Decoder, Encoder};
+
+const MAGIC: [u8; 4] = [0x23, 0x33, 0x23, 0x33];
+
+// 4 bytes magic + 4 bytes data length + 1 byte compression + 8 bytes
+// timestamp + 8 bytes seq, all authenticated along with the data
+const AAD_LEN: usize = 4 + 4 + 1 + 8 + 8;
+
+// authenticated header + 12 bytes nonce
+pub const HEADER_LEN: usize = AAD_LEN + 12;
+
+// aes-gcm adds a 16 bytes tag
+const TAG_LEN: usize = 16;
+
+/// size limits of the frames of a connection, in bytes
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FrameLimits {
+    /// max size of a frame on the wire, checked before it is buffered
+    pub max_frame_size: usize,
+    /// max size of a message once decompressed
+    pub max_message_size: usize,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 16 * 1024 * 1024,
+            max_message_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),
+    /// the stream is not made of our frames
+    BadMagic,
+    UnknownCompression(u8),
+    FrameTooLarge {
+        len: usize,
+        max: usize,
+    },
+    MessageTooLarge {
+        max: usize,
+    },
+    /// the frame was not encrypted with any of our keys
+    Unauthenticated,
+    /// an authentic frame that was already received
+    Replayed,
+    /// an authentic frame whose timestamp is out of the accepted skew
+    Stale,
+    Compress(io::Error),
+    Decompress(io::Error),
+    Serialize(serde_json::Error),
+    Deserialize(serde_json::Error),
+    /// no keys to send with yet
+    NoKeys,
+}
+
+impl FrameError {
+    /// an authentic frame was rejected, or the peer could not prove it holds
+    /// the keys
+    pub fn is_security(&self) -> bool {
+        matches!(
+            self,
+            FrameError::Unauthenticated | FrameError::Replayed | FrameError::Stale
+        )
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "io error: {}", e),
+            FrameError::BadMagic => write!(f, "bad frame magic"),
+            FrameError::UnknownCompression(flag) => write!(f, "unknown compression {}", flag),
+            FrameError::FrameTooLarge { len, max } => {
+                write!(f, "frame of {} bytes exceeds the limit of {}", len, max)
+            }
+            FrameError::MessageTooLarge { max } => {
+                write!(f, "message exceeds the limit of {} bytes", max)
+            }
+            FrameError::Unauthenticated => write!(f, "frame failed to authenticate"),
+            FrameError::Replayed => write!(f, "replayed frame"),
+            FrameError::Stale => write!(f, "stale frame"),
+            FrameError::Compress(e) => write!(f, "compress error: {}", e),
+            FrameError::Decompress(e) => write!(f, "decompress error: {}", e),
+            FrameError::Serialize(e) => write!(f, "serialize error: {}", e),
+            FrameError::Deserialize(e) => write!(f, "deserialize error: {}", e),
+            FrameError::NoKeys => write!(f, "no session keys"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+impl From<FrameError> for io::Error {
+    fn from(e: FrameError) -> Self {
+        match e {
+            FrameError::Io(e) => e,
+            e if e.is_security() => io::Error::new(ErrorKind::PermissionDenied, e),
+            e => io::Error::new(ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/// The decrypted and decompressed content of a frame, in json so that a peer
+/// ignores the fields it does not know yet.
+pub struct Frame(Vec<u8>);
+
+impl Frame {
+    pub fn parse<T: DeserializeOwned>(&self) -> Result<T, FrameError> {
+        serde_json::from_slice(&self.0).map_err(FrameError::Deserialize)
+    }
+}
+
+fn random_nonce() -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    let mut rng = rand::thread_rng();
+    rng.fill(&mut nonce);
+    nonce
+}
+
+/// Frames of the control channel, encrypted with the session keys.
+///
+/// Until the keys are known it fails to decode anything; a server unsure
+/// which keys an agent uses sets all candidates and the first frame decides.
+/// The keys are those of one connection, so are the sequence numbers and the
+/// replay window: a frame replayed on another connection fails to
+/// authenticate.
+pub struct FrameCodec {
+    keys: Vec<SessionKeys>,
+    matched: Option<usize>,
+    /// sequence number of the next frame we send
+    seq: u64,
+    replay: ReplayWindow,
+    limits: FrameLimits,
+    compression: Compression,
+    /// bodies smaller than this are sent uncompressed
+    threshold: usize,
+}
+
+impl FrameCodec {
+    pub fn new(limits: FrameLimits) -> Self {
+        Self {
+            keys: vec![],
+            matched: None,
+            seq: 0,
+            replay: ReplayWindow::default(),
+            limits,
+            compression: Compression::None,
+            threshold: 0,
+        }
+    }
+
+    /// compress the frames we send, frames of any compression are received
+    pub fn set_compression(&mut self, compression: Compression, threshold: usize) {
+        self.compression = compression;
+        self.threshold = threshold;
+    }
+
+    /// use the first of `keys` a frame from the peer is encrypted with
+    pub fn set_keys(&mut self, keys: Vec<SessionKeys>) {
+        self.matched = if keys.len() == 1 { Some(0) } else { None };
+        self.keys = keys;
+    }
+
+    /// index of the keys the peer has proven to hold
+    pub fn matched(&self) -> Option<usize> {
+        self.matched
+    }
+
+    fn open(&mut self, aad: &[u8], nonce: &[u8], encbytes: &[u8]) -> Result<Vec<u8>, FrameError> {
+        let nonce = Nonce::from_slice(nonce);
+        let candidates = match self.matched {
+            Some(i) => i..i + 1,
+            None => 0..self.keys.len(),
+        };
+        for i in candidates {
+            let cipher = Aes256Gcm::new((&self.keys[i].rx).into());
+            let payload = Payload { msg: encbytes, aad };
+            if let Ok(bytes) = cipher.decrypt(nonce, payload) {
+                self.matched = Some(i);
+                return Ok(bytes);
+            }
+        }
+        Err(FrameError::Unauthenticated)
+    }
+
+    fn tx(&self) -> Result<&Key, FrameError> {
+        self.matched
+            .map(|i| &self.keys[i].tx)
+            .ok_or(FrameError::NoKeys)
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = FrameError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, FrameError> {
+        if src.len() >= MAGIC.len() && src[..MAGIC.len()] != MAGIC {
+            return Err(FrameError::BadMagic);
+        }
+        if src.len() < HEADER_LEN {
+            return Ok(None);
+        }
+
+        let len = u32::from_be_bytes([src[4], src[5], src[6], src[7]]) as usize;
+        if len > self.limits.max_frame_size {
+            return Err(FrameError::FrameTooLarge {
+                len,
+                max: self.limits.max_frame_size,
+            });
+        }
+        if src.len() < HEADER_LEN + len {
+            src.reserve(HEADER_LEN + len - src.len());
+            return Ok(None);
+        }
+
+        let header = src.split_to(HEADER_LEN);
+        let (aad, nonce) = header.split_at(AAD_LEN);
+        let encbytes = src.split_to(len);
+        let bytes = self.open(aad, nonce, &encbytes)?;
+
+        // only trust the header once authenticated
+        let mut aad = &aad[8..];
+        let flag = aad.get_u8();
+        self.replay.check(aad.get_u64(), aad.get_u64())?;
+
+        let compression =
+            Compression::from_flag(flag).ok_or(FrameError::UnknownCompression(flag))?;
+        let objbytes = compression.decompress(&bytes, self.limits.max_message_size)?;
+        Ok(Some(Frame(objbytes)))
+    }
+}
+
+impl<T: Serialize> Encoder<T> for FrameCodec {
+    type Error = FrameError;
+
+    fn encode(&mut self, t: T, buf: &mut BytesMut) -> Result<(), FrameError> {
+        let key = *self.tx()?;
+
+        let bytes = serde_json::to_vec(&t).map_err(FrameError::Serialize)?;
+        if bytes.len() > self.limits.max_message_size {
+            return Err(FrameError::MessageTooLarge {
+                max: self.limits.max_message_size,
+            });
+        }
+
+        let compression = if bytes.len() < self.threshold {
+            Compression::None
+        } else {
+            self.compression
+        };
+        let bytes = compression.compress(&bytes).map_err(FrameError::Compress)?;
+
+        let len = bytes.len() + TAG_LEN;
+        if len > self.limits.max_frame_size {
+            return Err(FrameError::FrameTooLarge {
+                len,
+                max: self.limits.max_frame_size,
+            });
+        }
+
+        let mut aad = Vec::with_capacity(AAD_LEN);
+        aad.put_slice(&MAGIC);
+        aad.put_u32(len as u32);
+        aad.put_u8(compression as u8);
+        aad.put_u64(now_millis());
+        aad.put_u64(self.seq);
+        self.seq += 1;
+
+        let cipher = Aes256Gcm::new((&key).into());
+        let nonce = random_nonce();
+        let payload = Payload {
+            msg: &bytes,
+            aad: &aad,
+        };
+        // only fails for messages larger than aes-gcm allows, which the
+        // limits above rule out
+        let encbytes = cipher.encrypt(&Nonce::from(nonce), payload).map_err(|_| {
+            FrameError::FrameTooLarge {
+                len,
+                max: self.limits.max_frame_size,
+            }
+        })?;
+
+        buf.reserve(HEADER_LEN + encbytes.len());
+        buf.put_slice(&aad);
+        buf.put_slice(&nonce);
+        buf.put_slice(&encbytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::COMPRESSIONS;
+
+    /// a codec able to decode its own frames
+    fn codec(limits: FrameLimits, compression: Compression) -> FrameCodec {
+        let mut codec = FrameCodec::new(limits);
+        let keys = SessionKeys {
+            tx: [7; 32],
+            rx: [7; 32],
+        };
+        codec.set_keys(vec![keys]);
+        codec.set_compression(compression, 0);
+        codec
+    }
+
+    fn encode(codec: &mut FrameCodec, msg: &str) -> BytesMut {
+        let mut buf = BytesMut::new();
+        codec.encode(msg, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn round_trip() {
+        let msg = "hello ".repeat(1000);
+        for compression in COMPRESSIONS {
+            let mut codec = codec(FrameLimits::default(), compression);
+            let mut buf = encode(&mut codec, &msg);
+            buf.extend_from_slice(&encode(&mut codec, "again"));
+
+            let frame = codec.decode(&mut buf).unwrap().unwrap();
+            assert_eq!(frame.parse::<String>().unwrap(), msg);
+            let frame = codec.decode(&mut buf).unwrap().unwrap();
+            assert_eq!(frame.parse::<String>().unwrap(), "again");
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn partial_frame() {
+        let mut codec = codec(FrameLimits::default(), Compression::None);
+        let frame = encode(&mut codec, "hello");
+        let mut buf = BytesMut::from(&frame[..frame.len() - 1]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&frame[frame.len() - 1..]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut codec = codec(FrameLimits::default(), Compression::None);
+        let mut buf = BytesMut::from(&b"GET / HTTP/1.1\r\n"[..]);
+        assert!(matches!(codec.decode(&mut buf), Err(FrameError::BadMagic)));
+    }
+
+    #[test]
+    fn frame_too_large() {
+        let limits = FrameLimits {
+            max_frame_size: 1024,
+            ..Default::default()
+        };
+        // rejected from the header, before the body is buffered
+        let mut header = BytesMut::from(&MAGIC[..]);
+        header.put_u32(1025);
+        header.resize(HEADER_LEN, 0);
+        let mut codec = codec(limits, Compression::None);
+        assert!(matches!(
+            codec.decode(&mut header),
+            Err(FrameError::FrameTooLarge {
+                len: 1025,
+                max: 1024
+            })
+        ));
+
+        let mut buf = BytesMut::new();
+        let e = codec.encode("x".repeat(1024), &mut buf).unwrap_err();
+        assert!(matches!(e, FrameError::FrameTooLarge { max: 1024, .. }));
+    }
+
+    #[test]
+    fn message_too_large() {
+        let limits = FrameLimits {
+            max_message_size: 1024,
+            ..Default::default()
+        };
+        let mut codec = codec(limits, Compression::Zstd);
+        let mut buf = BytesMut::new();
+        let e = codec.encode("x".repeat(1024), &mut buf).unwrap_err();
+        assert!(matches!(e, FrameError::MessageTooLarge { max: 1024 }));
+    }
+
+    #[test]
+    fn bomb() {
+        // a tiny frame that decompresses way past the limit of the receiver
+        let msg = "0".repeat(1 << 20);
+        for compression in [Compression::Zlib, Compression::Zstd] {
+            let mut buf = encode(&mut codec(FrameLimits::default(), compression), &msg);
+            assert!(buf.len() < 64 * 1024);
+            let limits = FrameLimits {
+                max_message_size: 64 * 1024,
+                ..Default::default()
+            };
+            assert!(matches!(
+                codec(limits, compression).decode(&mut buf),
+                Err(FrameError::MessageTooLarge { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn tampered() {
+        let mut codec = codec(FrameLimits::default(), Compression::None);
+        let mut buf = encode(&mut codec, "hello");
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(FrameError::Unauthenticated)
+        ));
+
+        // the header is authenticated as well
+        let mut buf = encode(&mut codec, "hello");
+        buf[8] = Compression::Zlib as u8;
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(FrameError::Unauthenticated)
+        ));
+    }
+
+    #[test]
+    fn replayed() {
+        let mut codec = codec(FrameLimits::default(), Compression::None);
+        let frame = encode(&mut codec, "hello");
+        assert!(codec.decode(&mut frame.clone()).unwrap().is_some());
+        assert!(matches!(
+            codec.decode(&mut frame.clone()),
+            Err(FrameError::Replayed)
+        ));
+    }
+
+    #[test]
+    fn no_keys() {
+        let mut codec = FrameCodec::new(FrameLimits::default());
+        let mut buf = BytesMut::new();
+        assert!(matches!(
+            codec.encode("hello", &mut buf),
+            Err(FrameError::NoKeys)
+        ));
+    }
+}
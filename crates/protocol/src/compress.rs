@@ -0,0 +1,152 @@
+use crate::FrameError;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder};
+use std::io::{self, Read, Write};
+
+/// compression of a frame body, flagged in its header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None = 0,
+    Zlib = 1,
+    Zstd = 2,
+}
+
+/// every compression this build handles, best first
+pub const COMPRESSIONS: [Compression; 3] =
+    [Compression::Zstd, Compression::Zlib, Compression::None];
+
+impl Compression {
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zlib => "zlib",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        COMPRESSIONS.into_iter().find(|c| c.name() == name)
+    }
+
+    pub fn from_flag(flag: u8) -> Option<Self> {
+        COMPRESSIONS.into_iter().find(|c| *c as u8 == flag)
+    }
+
+    /// the first of the compressions an agent listed in its `Capabilities`
+    /// we know of, both ends send with it
+    pub fn negotiate(names: &[String]) -> Self {
+        names
+            .iter()
+            .find_map(|name| Self::from_name(name))
+            .unwrap_or(Compression::None)
+    }
+
+    pub fn compress(self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            Compression::Zlib => {
+                let mut e = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                e.write_all(bytes)?;
+                e.finish()
+            }
+            Compression::Zstd => zstd::bulk::compress(bytes, zstd::DEFAULT_COMPRESSION_LEVEL),
+        }
+    }
+
+    /// decompress at most `max` bytes, larger messages are an error rather
+    /// than a way to make us allocate without bound
+    pub fn decompress(self, bytes: &[u8], max: usize) -> Result<Vec<u8>, FrameError> {
+        let mut reader: Box<dyn Read + '_> = match self {
+            Compression::None => Box::new(bytes),
+            Compression::Zlib => Box::new(ZlibDecoder::new(bytes)),
+            Compression::Zstd => {
+                Box::new(zstd::stream::read::Decoder::new(bytes).map_err(FrameError::Decompress)?)
+            }
+        };
+
+        // read one byte past the limit to tell a bomb from a message of
+        // exactly the max size
+        let mut out = Vec::new();
+        reader
+            .by_ref()
+            .take(max as u64 + 1)
+            .read_to_end(&mut out)
+            .map_err(FrameError::Decompress)?;
+        if out.len() > max {
+            return Err(FrameError::MessageTooLarge { max });
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn negotiate() {
+        assert_eq!(
+            Compression::negotiate(&names(&["zlib", "zstd"])),
+            Compression::Zlib
+        );
+        assert_eq!(
+            Compression::negotiate(&names(&["lz4", "zstd"])),
+            Compression::Zstd
+        );
+        assert_eq!(Compression::negotiate(&names(&["lz4"])), Compression::None);
+        assert_eq!(Compression::negotiate(&[]), Compression::None);
+    }
+
+    #[test]
+    fn flags() {
+        for compression in COMPRESSIONS {
+            assert_eq!(Compression::from_flag(compression as u8), Some(compression));
+            assert_eq!(
+                Compression::from_name(compression.name()),
+                Some(compression)
+            );
+        }
+        assert_eq!(Compression::from_flag(3), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = b"hello hello hello hello".repeat(100);
+        for compression in COMPRESSIONS {
+            let compressed = compression.compress(&bytes).unwrap();
+            let out = compression.decompress(&compressed, bytes.len()).unwrap();
+            assert_eq!(out, bytes, "{}", compression.name());
+        }
+    }
+
+    #[test]
+    fn bomb() {
+        let bytes = vec![0u8; 1 << 20];
+        for compression in COMPRESSIONS {
+            let compressed = compression.compress(&bytes).unwrap();
+            let e = compression
+                .decompress(&compressed, bytes.len() - 1)
+                .unwrap_err();
+            assert!(
+                matches!(e, FrameError::MessageTooLarge { max } if max == bytes.len() - 1),
+                "{}",
+                compression.name()
+            );
+        }
+    }
+
+    #[test]
+    fn corrupted() {
+        for compression in [Compression::Zlib, Compression::Zstd] {
+            let e = compression.decompress(b"not compressed", 1024).unwrap_err();
+            assert!(
+                matches!(e, FrameError::Decompress(_)),
+                "{}",
+                compression.name()
+            );
+        }
+    }
+}
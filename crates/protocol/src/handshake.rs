@@ -0,0 +1,252 @@
+use crate::{TaskSpec, TaskType, TriggerSpec};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// version of the protocol, bumped whenever a message gains something an
+/// older peer would not understand; see CHANGELOG.md
+pub const PROTOCOL_VERSION: u16 = 16;
+
+/// oldest version this build can still talk, only raised when the framing or
+/// the greeting change; what came later is gated on the negotiated version
+pub const MIN_PROTOCOL_VERSION: u16 = 13;
+
+/// starts a connection, so that a peer speaking something else entirely is
+/// told apart from one speaking another version
+const GREETING_MAGIC: [u8; 4] = *b"FAGT";
+
+pub const GREETING_LEN: usize = GREETING_MAGIC.len() + 2 + 2;
+
+/// Versions both ends send in clear ahead of the key exchange, the agent
+/// follows it with its id and the server answers with a `Verdict`.
+///
+/// The greeting, the agent id and the verdict must never change, so that any
+/// two versions can still find out whether they are able to talk. They are
+/// repeated in the authenticated `Hello` and `Welcome`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Greeting {
+    pub protocol_version: u16,
+    pub min_protocol_version: u16,
+}
+
+impl Greeting {
+    /// the versions of this build
+    pub fn ours() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; GREETING_LEN] {
+        let mut buf = [0u8; GREETING_LEN];
+        buf[..4].copy_from_slice(&GREETING_MAGIC);
+        buf[4..6].copy_from_slice(&self.protocol_version.to_be_bytes());
+        buf[6..].copy_from_slice(&self.min_protocol_version.to_be_bytes());
+        buf
+    }
+
+    /// None if the peer does not start with a greeting
+    pub fn from_bytes(buf: &[u8; GREETING_LEN]) -> Option<Self> {
+        if buf[..4] != GREETING_MAGIC {
+            return None;
+        }
+        Some(Self {
+            protocol_version: u16::from_be_bytes([buf[4], buf[5]]),
+            min_protocol_version: u16::from_be_bytes([buf[6], buf[7]]),
+        })
+    }
+
+    /// the highest version both ends speak
+    pub fn negotiate(&self) -> Result<u16, String> {
+        let version = self.protocol_version.min(PROTOCOL_VERSION);
+        let min_version = self.min_protocol_version.max(MIN_PROTOCOL_VERSION);
+        if version < min_version {
+            return Err(format!(
+                "peer speaks protocol versions {} to {}, we speak {} to {}",
+                self.min_protocol_version,
+                self.protocol_version,
+                MIN_PROTOCOL_VERSION,
+                PROTOCOL_VERSION
+            ));
+        }
+        Ok(version)
+    }
+}
+
+/// answer of the server to the greeting of an agent, followed by its
+/// ephemeral key if accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Verdict {
+    Accepted = 0,
+    UnsupportedVersion = 1,
+    UnknownAgent = 2,
+    /// the agent is known but has no public key to authenticate it with,
+    /// only sent from protocol version 15
+    KeyNotRegistered = 3,
+}
+
+impl Verdict {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Verdict::Accepted),
+            1 => Some(Verdict::UnsupportedVersion),
+            2 => Some(Verdict::UnknownAgent),
+            3 => Some(Verdict::KeyNotRegistered),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Verdict::Accepted => write!(f, "accepted"),
+            Verdict::UnsupportedVersion => write!(f, "unsupported protocol version"),
+            Verdict::UnknownAgent => write!(f, "unknown agent"),
+            Verdict::KeyNotRegistered => write!(f, "no public key registered for the agent"),
+        }
+    }
+}
+
+impl std::error::Error for Verdict {}
+
+/// what an agent is able to handle
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Capabilities {
+    pub task_types: Vec<String>,
+    pub trigger_types: Vec<String>,
+    pub compression: Vec<String>,
+}
+
+impl Capabilities {
+    /// whether an agent talking protocol `version` is able to run `spec`
+    pub fn supports(&self, spec: &TaskSpec, version: u16) -> bool {
+        spec.min_version() <= version
+            && self.task_types.iter().any(|t| t == spec.task.kind())
+            && spec
+                .triggers
+                .iter()
+                .all(|trig| self.trigger_types.iter().any(|t| t == trig.kind()))
+    }
+}
+
+/// First frame sent by the agent on every connection, its versions must be
+/// those of its greeting.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hello {
+    pub agent_id: Uuid,
+    pub protocol_version: u16,
+    pub min_protocol_version: u16,
+    pub agent_version: String,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    /// the greeting the versions were sent with in clear
+    pub fn greeting(&self) -> Greeting {
+        Greeting {
+            protocol_version: self.protocol_version,
+            min_protocol_version: self.min_protocol_version,
+        }
+    }
+}
+
+/// Server answer to `Hello`, sent as `Result<Welcome, String>`; the version
+/// is the one negotiated from the greetings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Welcome {
+    pub protocol_version: u16,
+    pub server_version: String,
+}
+
+impl TaskSpec {
+    /// oldest protocol version able to carry all the options set in the
+    /// spec; an option added later raises it while set, so that an older
+    /// agent does not get the task rather than silently ignore the option
+    pub fn min_version(&self) -> u16 {
+        match &self.task {
+            TaskType::FileUpdate(spec) if spec.mode.is_some() => 16,
+            TaskType::Command(spec) if spec.umask.is_some() => 14,
+            _ => MIN_PROTOCOL_VERSION,
+        }
+    }
+}
+
+impl TaskType {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TaskType::FileUpdate(_) => "FileUpdate",
+            TaskType::Command(_) => "Command",
+            TaskType::Hosts(_) => "Hosts",
+        }
+    }
+}
+
+impl TriggerSpec {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TriggerSpec::Cron(_) => "Cron",
+            TriggerSpec::Immediate => "Immediate",
+            TriggerSpec::Startup => "Startup",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn greeting_round_trip() {
+        let greeting = Greeting {
+            protocol_version: 20,
+            min_protocol_version: 14,
+        };
+        assert_eq!(Greeting::from_bytes(&greeting.to_bytes()), Some(greeting));
+    }
+
+    #[test]
+    fn greeting_bad_magic() {
+        let mut bytes = Greeting::ours().to_bytes();
+        bytes[0] ^= 0xff;
+        assert_eq!(Greeting::from_bytes(&bytes), None);
+    }
+
+    #[test]
+    fn negotiate_highest_common_version() {
+        let newer = Greeting {
+            protocol_version: PROTOCOL_VERSION + 5,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+        };
+        assert_eq!(newer.negotiate(), Ok(PROTOCOL_VERSION));
+        assert_eq!(Greeting::ours().negotiate(), Ok(PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn negotiate_without_common_version() {
+        let older = Greeting {
+            protocol_version: MIN_PROTOCOL_VERSION - 1,
+            min_protocol_version: 1,
+        };
+        assert!(older.negotiate().is_err());
+        let newer = Greeting {
+            protocol_version: PROTOCOL_VERSION + 2,
+            min_protocol_version: PROTOCOL_VERSION + 1,
+        };
+        assert!(newer.negotiate().is_err());
+    }
+
+    #[test]
+    fn verdict_from_u8() {
+        for verdict in [
+            Verdict::Accepted,
+            Verdict::UnsupportedVersion,
+            Verdict::UnknownAgent,
+            Verdict::KeyNotRegistered,
+        ] {
+            assert_eq!(Verdict::from_u8(verdict as u8), Some(verdict));
+        }
+        assert_eq!(Verdict::from_u8(0xff), None);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::Key;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+pub use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+
+// ephemeral public key sent in clear by both ends ahead of the first frame
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+const KEX_LABEL: &[u8] = b"file-agent kex x25519-sha256-aesgcm";
+
+/// keys of one connection, one per direction
+#[derive(Clone)]
+pub struct SessionKeys {
+    pub tx: Key,
+    pub rx: Key,
+}
+
+pub fn generate_secret() -> StaticSecret {
+    StaticSecret::random_from_rng(rand::rngs::OsRng)
+}
+
+pub fn encode_public_key(key: &PublicKey) -> String {
+    STANDARD.encode(key.as_bytes())
+}
+
+pub fn encode_secret_key(key: &StaticSecret) -> String {
+    STANDARD.encode(key.as_bytes())
+}
+
+fn parse_key_bytes(s: &str) -> Result<[u8; 32], String> {
+    let bytes = STANDARD.decode(s.trim()).map_err(|e| e.to_string())?;
+    bytes
+        .try_into()
+        .map_err(|_| "key must be 32 bytes".to_string())
+}
+
+pub fn parse_public_key(s: &str) -> Result<PublicKey, String> {
+    parse_key_bytes(s).map(PublicKey::from)
+}
+
+pub fn parse_secret_key(s: &str) -> Result<StaticSecret, String> {
+    parse_key_bytes(s).map(StaticSecret::from)
+}
+
+/// Mix the three DH results and the agent's pre-shared key into the session
+/// keys, bound to the whole handshake transcript.
+///
+/// `ee` gives forward secrecy, `se` (agent static, server ephemeral) can only
+/// be computed by the agent and `es` (agent ephemeral, server static) only by
+/// the server, so each end authenticates the other by decrypting its first
+/// frame.
+fn derive(
+    agent_id: &Uuid,
+    agent_ephemeral: &PublicKey,
+    server_ephemeral: &PublicKey,
+    dh: [SharedSecret; 3],
+    psk: &Key,
+) -> Option<(Key, Key)> {
+    // a low order point of the peer would make the result predictable
+    if dh.iter().any(|s| !s.was_contributory()) {
+        return None;
+    }
+
+    let transcript = Sha256::new()
+        .chain_update(KEX_LABEL)
+        .chain_update(agent_id.as_bytes())
+        .chain_update(agent_ephemeral.as_bytes())
+        .chain_update(server_ephemeral.as_bytes())
+        .finalize();
+
+    let mut ikm = Vec::with_capacity(4 * 32);
+    for s in &dh {
+        ikm.extend_from_slice(s.as_bytes());
+    }
+    ikm.extend_from_slice(psk);
+
+    let hk = Hkdf::<Sha256>::new(Some(&transcript), &ikm);
+    let mut to_server = Key::default();
+    let mut to_agent = Key::default();
+    hk.expand(b"agent to server", &mut to_server).ok()?;
+    hk.expand(b"server to agent", &mut to_agent).ok()?;
+    Some((to_server, to_agent))
+}
+
+/// session keys of the agent, None if the server sent a weak key
+pub fn agent_session_keys(
+    agent_id: &Uuid,
+    secret: &StaticSecret,
+    ephemeral: &StaticSecret,
+    server_public: &PublicKey,
+    server_ephemeral: &PublicKey,
+    psk: &Key,
+) -> Option<SessionKeys> {
+    let dh = [
+        ephemeral.diffie_hellman(server_ephemeral),
+        secret.diffie_hellman(server_ephemeral),
+        ephemeral.diffie_hellman(server_public),
+    ];
+    let (tx, rx) = derive(
+        agent_id,
+        &PublicKey::from(ephemeral),
+        server_ephemeral,
+        dh,
+        psk,
+    )?;
+    Some(SessionKeys { tx, rx })
+}
+
+/// session keys of the server, None if the agent sent a weak key
+pub fn server_session_keys(
+    agent_id: &Uuid,
+    secret: &StaticSecret,
+    ephemeral: &StaticSecret,
+    agent_public: &PublicKey,
+    agent_ephemeral: &PublicKey,
+    psk: &Key,
+) -> Option<SessionKeys> {
+    let dh = [
+        ephemeral.diffie_hellman(agent_ephemeral),
+        ephemeral.diffie_hellman(agent_public),
+        secret.diffie_hellman(agent_ephemeral),
+    ];
+    let (rx, tx) = derive(
+        agent_id,
+        agent_ephemeral,
+        &PublicKey::from(ephemeral),
+        dh,
+        psk,
+    )?;
+    Some(SessionKeys { tx, rx })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the long-term keys of an agent and a server
+    struct Peers {
+        id: Uuid,
+        agent: StaticSecret,
+        server: StaticSecret,
+    }
+
+    impl Peers {
+        fn new() -> Self {
+            Self {
+                id: Uuid::new_v4(),
+                agent: generate_secret(),
+                server: generate_secret(),
+            }
+        }
+
+        /// the keys each end derives, with what each knows of the other
+        fn exchange(
+            &self,
+            agent_psk: &Key,
+            server_psk: &Key,
+            server_public: &PublicKey,
+            agent_public: &PublicKey,
+        ) -> (SessionKeys, SessionKeys) {
+            let agent_ephemeral = generate_secret();
+            let server_ephemeral = generate_secret();
+            let agent = agent_session_keys(
+                &self.id,
+                &self.agent,
+                &agent_ephemeral,
+                server_public,
+                &PublicKey::from(&server_ephemeral),
+                agent_psk,
+            )
+            .unwrap();
+            let server = server_session_keys(
+                &self.id,
+                &self.server,
+                &server_ephemeral,
+                agent_public,
+                &PublicKey::from(&agent_ephemeral),
+                server_psk,
+            )
+            .unwrap();
+            (agent, server)
+        }
+
+        fn agent_public(&self) -> PublicKey {
+            PublicKey::from(&self.agent)
+        }
+
+        fn server_public(&self) -> PublicKey {
+            PublicKey::from(&self.server)
+        }
+    }
+
+    fn agrees(agent: &SessionKeys, server: &SessionKeys) -> bool {
+        agent.tx == server.rx && agent.rx == server.tx
+    }
+
+    #[test]
+    fn same_keys() {
+        let p = Peers::new();
+        let psk = [1; 32];
+        let (agent, server) = p.exchange(&psk, &psk, &p.server_public(), &p.agent_public());
+        assert!(agrees(&agent, &server));
+        assert_ne!(agent.tx, agent.rx);
+
+        // every connection gets its own keys
+        let (again, _) = p.exchange(&psk, &psk, &p.server_public(), &p.agent_public());
+        assert_ne!(again.tx, agent.tx);
+    }
+
+    #[test]
+    fn wrong_psk() {
+        let p = Peers::new();
+        let (agent, server) = p.exchange(&[1; 32], &[2; 32], &p.server_public(), &p.agent_public());
+        assert!(!agrees(&agent, &server));
+    }
+
+    #[test]
+    fn wrong_static_key() {
+        let p = Peers::new();
+        let psk = [1; 32];
+        let other = PublicKey::from(&generate_secret());
+        // a server impersonated to the agent, an agent to the server
+        let (agent, server) = p.exchange(&psk, &psk, &other, &p.agent_public());
+        assert!(!agrees(&agent, &server));
+        let (agent, server) = p.exchange(&psk, &psk, &p.server_public(), &other);
+        assert!(!agrees(&agent, &server));
+    }
+
+    #[test]
+    fn weak_key() {
+        let p = Peers::new();
+        let low_order = PublicKey::from([0; 32]);
+        let keys = server_session_keys(
+            &p.id,
+            &p.server,
+            &generate_secret(),
+            &p.agent_public(),
+            &low_order,
+            &[1; 32],
+        );
+        assert!(keys.is_none());
+    }
+}
@@ -1,16 +1,105 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::{collections::HashMap, error::Error, fmt::Display, io, path::PathBuf, time::SystemTime};
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Action {
-    Retry { times: u8, interval: u64 },
+    /// retry up to `times` times, waiting `interval` seconds between attempts
+    Retry {
+        times: u8,
+        interval: u64,
+    },
+    /// like `Retry`, but the interval doubles after each attempt up to `max_interval`
+    Backoff {
+        times: u8,
+        interval: u64,
+        max_interval: u64,
+    },
+    /// like `Retry`, plus a random delay of up to `jitter` seconds
+    Jitter {
+        times: u8,
+        interval: u64,
+        jitter: u64,
+    },
     Ignore,
 }
 
+/// what to do when a task is triggered while it is still running
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Concurrency {
+    /// skip the new run
+    Forbid,
+    /// run it after the running one, skipping it if `max` runs already wait
+    Queue { max: usize },
+    /// kill the running one
+    Replace,
+    /// run up to `max` at the same time, skipping the new run beyond
+    Allow { max: usize },
+}
+
+impl Default for Concurrency {
+    fn default() -> Self {
+        Concurrency::Queue { max: 1 }
+    }
+}
+
+/// what to do on startup about the fire times of a cron trigger missed while
+/// the agent was down, or suspended
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum CatchUp {
+    /// skip them
+    #[default]
+    None,
+    /// run once for all of them
+    Once,
+    /// run once for each of them, for the latest `max` at most
+    All { max: usize },
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, Hash)]
+pub struct CronSpec {
+    pub expr: String,
+    pub catch_up: CatchUp,
+}
+
+impl<'de> Deserialize<'de> for CronSpec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Full {
+            expr: String,
+            #[serde(default)]
+            catch_up: CatchUp,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Json {
+            Expr(String),
+            Full(Full),
+        }
+
+        // task files written before `catch_up` hold the bare expression
+        let full = if deserializer.is_human_readable() {
+            match Json::deserialize(deserializer)? {
+                Json::Expr(expr) => Full {
+                    expr,
+                    catch_up: CatchUp::default(),
+                },
+                Json::Full(full) => full,
+            }
+        } else {
+            Full::deserialize(deserializer)?
+        };
+        Ok(CronSpec {
+            expr: full.expr,
+            catch_up: full.catch_up,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum TriggerSpec {
-    Cron(String),
+    Cron(CronSpec),
     Immediate,
     Startup,
 }
@@ -19,6 +108,31 @@ pub enum TriggerSpec {
 pub struct FileSpec {
     pub path: String,
     pub url: String,
+    /// expected hex sha256 of the content, a mismatch leaves the file untouched
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// max bytes downloaded, larger content is rejected
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// file mode as an octal string such as `"644"`, defaults to the mode of
+    /// the replaced file
+    #[serde(default)]
+    pub mode: Option<Mode>,
+    /// user name or uid owning the file, requires the agent to be root
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// group name or gid owning the file, defaults to the primary group of `owner`
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub create_parent_dirs: bool,
+    /// number of previous versions kept next to the file, as
+    /// `<name>.<timestamp>.bak`
+    #[serde(default)]
+    pub backup: usize,
+    /// command run after the file changed
+    #[serde(default)]
+    pub on_change: Option<CommandSpec>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -27,12 +141,127 @@ pub struct CommandSpec {
     pub args: Vec<String>,
     pub cwd: PathBuf,
     pub shell: bool,
+    /// max bytes kept for each of stdout and stderr, the rest is dropped
+    #[serde(default)]
+    pub output_limit: Option<usize>,
+    /// seconds before the whole process group is killed
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    /// extra environment variables
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// do not inherit the environment of the agent
+    #[serde(default)]
+    pub clear_env: bool,
+    /// user name or uid to run as, requires the agent to be root
+    #[serde(default)]
+    pub user: Option<String>,
+    /// group name or gid to run as, defaults to the primary group of `user`
+    #[serde(default)]
+    pub group: Option<String>,
+    /// file mode creation mask
+    #[serde(default)]
+    pub umask: Option<Umask>,
+}
+
+/// permission bits written as an octal string
+fn serialize_octal<S: serde::Serializer>(bits: u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{:03o}", bits))
+}
+
+/// parse the octal string of permission bits up to `max`, `what` and
+/// `example` tell what was expected
+fn parse_octal<E: serde::de::Error>(
+    s: &str,
+    max: u32,
+    what: &str,
+    example: &str,
+) -> Result<u32, E> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|bits| *bits <= max)
+        .ok_or_else(|| {
+            E::custom(format!(
+                "invalid {} {:?}, expected octal such as \"{}\"",
+                what, s, example
+            ))
+        })
+}
+
+/// A file mode creation mask, written as an octal string such as `"022"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Umask(pub u32);
+
+impl Serialize for Umask {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_octal(self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Umask {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        parse_octal(&s, 0o777, "umask", "022").map(Umask)
+    }
+}
+
+/// A file mode, written as an octal string such as `"644"` or `"2750"`.
+/// The plain number older versions wrote is still read as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mode(pub u32);
+
+impl Serialize for Mode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_octal(self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Mode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Written {
+            Octal(String),
+            Number(u32),
+        }
+
+        match Written::deserialize(deserializer)? {
+            Written::Octal(s) => parse_octal(&s, 0o7777, "mode", "644").map(Mode),
+            Written::Number(mode) if mode <= 0o7777 => Ok(Mode(mode)),
+            Written::Number(mode) => Err(serde::de::Error::custom(format!(
+                "invalid mode {:o}, above 7777",
+                mode
+            ))),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct HostSpec {
     pub ip: String,
+    /// hosts added to or removed from the line of `ip`, an absent entry
+    /// without hosts removes the whole line
     pub hosts: Vec<String>,
+    #[serde(default)]
+    pub state: HostState,
+    /// hosts file to edit, defaults to the one of the os
+    #[serde(default)]
+    pub path: Option<String>,
+    /// only report the diff of what would be written
+    #[serde(default)]
+    pub dry_run: bool,
+    /// command run after the hosts file changed
+    #[serde(default)]
+    pub on_change: Option<CommandSpec>,
+}
+
+/// whether the entry of a `HostSpec` is wanted in the hosts file
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HostState {
+    #[default]
+    Present,
+    Absent,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -48,8 +277,23 @@ pub struct TaskSpec {
     pub task: TaskType,
     pub on_error: Action,
     pub triggers: Vec<TriggerSpec>,
+    /// seconds before a run of any task type is aborted
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    #[serde(default)]
+    pub concurrency: Concurrency,
 }
 
+/// live state of a task on the agent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskStatus {
+    pub spec: TaskSpec,
+    pub activated: bool,
+    pub running: bool,
+}
+
+pub type AgentTaskStatus = HashMap<Uuid, TaskStatus>;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SyncConfig {
     pub pull: bool,
@@ -71,10 +315,24 @@ impl Display for TaskSpecError {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// what a successful run did to its target
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Change {
+    Updated,
+    Unchanged,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TaskResult {
     pub status: Option<i32>,
     pub message: String,
+    /// None for tasks that do not tell
+    #[serde(default)]
+    pub change: Option<Change>,
+    #[serde(default)]
+    pub stdout: String,
+    #[serde(default)]
+    pub stderr: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -85,6 +343,19 @@ pub enum TaskError {
     IoError(String),
     NetError(String),
     RuntimeError(String),
+    Timeout(u64),
+    UserNotFound(String),
+    GroupNotFound(String),
+    PrivilegeDrop(String),
+    HttpStatus(u16),
+    ChecksumMismatch {
+        expected: String,
+        actual: String,
+    },
+    TooLarge(u64),
+    NoBackup(String),
+    /// killed by a newer run under `Concurrency::Replace`
+    Replaced,
 }
 
 impl Display for TaskError {
@@ -96,6 +367,17 @@ impl Display for TaskError {
             TaskError::IoError(e) => write!(f, "io error: {}", e),
             TaskError::NetError(e) => write!(f, "net error: {}", e),
             TaskError::RuntimeError(e) => write!(f, "runtime error: {}", e),
+            TaskError::Timeout(secs) => write!(f, "timed out after {}s", secs),
+            TaskError::UserNotFound(e) => write!(f, "user not found: {}", e),
+            TaskError::GroupNotFound(e) => write!(f, "group not found: {}", e),
+            TaskError::PrivilegeDrop(e) => write!(f, "failed to drop privileges: {}", e),
+            TaskError::HttpStatus(status) => write!(f, "http status {}", status),
+            TaskError::ChecksumMismatch { expected, actual } => {
+                write!(f, "sha256 mismatch: expected {}, got {}", expected, actual)
+            }
+            TaskError::TooLarge(max) => write!(f, "content exceeds the limit of {} bytes", max),
+            TaskError::NoBackup(e) => write!(f, "no backup: {}", e),
+            TaskError::Replaced => write!(f, "replaced by a newer run"),
         }
     }
 }
@@ -123,6 +405,12 @@ pub enum EventType {
     TriggerInstall,
     Deactivate,
     Run,
+    Rollback,
+    /// the `on_change` command of a run or rollback that changed something
+    Hook,
+    /// a trigger fired while the task was running and `concurrency` did not
+    /// allow another run
+    Skipped,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -131,7 +419,74 @@ pub struct Event {
     pub type_: EventType,
     pub start: SystemTime,
     pub end: SystemTime,
+    /// 1-based attempt number of a `Run` event
+    #[serde(default)]
+    pub attempt: Option<u8>,
+    /// id of the event a `Hook` event follows
+    #[serde(default)]
+    pub parent: Option<Uuid>,
     pub result: Result<TaskResult, TaskError>,
 }
 
 pub type AgentEventLog = HashMap<Uuid, Vec<Event>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn umask_octal() {
+        let mask: Umask = serde_json::from_str("\"022\"").unwrap();
+        assert_eq!(mask, Umask(0o022));
+        assert_eq!(serde_json::to_string(&Umask(0o027)).unwrap(), "\"027\"");
+        assert_eq!(serde_json::to_string(&Umask(0)).unwrap(), "\"000\"");
+    }
+
+    #[test]
+    fn umask_invalid() {
+        for s in ["\"\"", "\"089\"", "\"1000\"", "\"0o22\"", "18"] {
+            assert!(serde_json::from_str::<Umask>(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn mode_octal() {
+        let mode: Mode = serde_json::from_str("\"644\"").unwrap();
+        assert_eq!(mode, Mode(0o644));
+        let mode: Mode = serde_json::from_str("\"2750\"").unwrap();
+        assert_eq!(mode, Mode(0o2750));
+        assert_eq!(serde_json::to_string(&Mode(0o600)).unwrap(), "\"600\"");
+        assert_eq!(serde_json::to_string(&Mode(0o4755)).unwrap(), "\"4755\"");
+        // as older versions wrote it
+        let mode: Mode = serde_json::from_str("420").unwrap();
+        assert_eq!(mode, Mode(0o644));
+    }
+
+    #[test]
+    fn mode_invalid() {
+        for s in ["\"\"", "\"089\"", "\"10000\"", "\"0o644\"", "4096", "-1"] {
+            assert!(serde_json::from_str::<Mode>(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn cron_spec_bare_expression() {
+        let spec: TriggerSpec = serde_json::from_str(r#"{"Cron":"0 * * * * *"}"#).unwrap();
+        assert_eq!(
+            spec,
+            TriggerSpec::Cron(CronSpec {
+                expr: "0 * * * * *".to_string(),
+                catch_up: CatchUp::None,
+            })
+        );
+        let spec: TriggerSpec =
+            serde_json::from_str(r#"{"Cron":{"expr":"0 * * * * *","catch_up":"Once"}}"#).unwrap();
+        assert_eq!(
+            spec,
+            TriggerSpec::Cron(CronSpec {
+                expr: "0 * * * * *".to_string(),
+                catch_up: CatchUp::Once,
+            })
+        );
+    }
+}
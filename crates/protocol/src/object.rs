@@ -1,22 +1,86 @@
 use crate::{AgentEventLog, TaskSpec};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt::Display;
 use uuid::Uuid;
 
+// requests are short lived, boxing the spec would not save anything
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
-    AddTask { id: Uuid, spec: TaskSpec },
-    RemoveTask { id: Uuid },
+    /// add or update a task on the agent
+    AddTask {
+        id: Uuid,
+        spec: TaskSpec,
+    },
+    /// remove a task from the agent
+    RemoveTask {
+        id: Uuid,
+    },
+    /// get the live task state of the agent
     ListTask,
+    /// make the agent re-read its local task file
     Reload,
-    PullTask { id: Uuid },
-    ReportStatus { id: Uuid, log: AgentEventLog },
+    /// restore the latest backup of the file of a FileUpdate task
+    Rollback {
+        id: Uuid,
+    },
+    PullTask {
+        id: Uuid,
+    },
+    /// turn the connection into a session where tasks are pushed on change
+    Subscribe {
+        id: Uuid,
+    },
+    Heartbeat {
+        id: Uuid,
+    },
+    ReportStatus {
+        id: Uuid,
+        log: AgentEventLog,
+        /// events the agent could not keep since the last report
+        dropped: u64,
+    },
 }
 
+/// why a request failed, so that the sender can react accordingly
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ResponseError {
+    /// the agent is not registered on the server, or was removed
+    UnknownAgent,
+    /// the request is not allowed on this connection
+    Unauthorized(String),
+    /// the request is not handled by the peer
+    Unsupported(String),
+    /// the object the request refers to does not exist
+    NotFound(String),
+    /// too many requests, retry after the given seconds
+    RateLimited(u64),
+    /// too much work in progress, retry after the given seconds
+    Busy(u64),
+    Internal(String),
+}
+
+impl Display for ResponseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResponseError::UnknownAgent => write!(f, "unknown agent"),
+            ResponseError::Unauthorized(e) => write!(f, "unauthorized: {}", e),
+            ResponseError::Unsupported(e) => write!(f, "unsupported: {}", e),
+            ResponseError::NotFound(e) => write!(f, "not found: {}", e),
+            ResponseError::RateLimited(secs) => write!(f, "rate limited, retry after {}s", secs),
+            ResponseError::Busy(secs) => write!(f, "busy, retry after {}s", secs),
+            ResponseError::Internal(e) => write!(f, "internal error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ResponseError {}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Response {
     Ok,
-    Error(String),
-    Object(Vec<u8>),
+    Error(ResponseError),
+    Object(serde_json::Value),
 }
 
 impl Response {
@@ -24,7 +88,7 @@ impl Response {
         Response::Ok
     }
 
-    pub fn err(err: String) -> Self {
+    pub fn err(err: ResponseError) -> Self {
         Response::Error(err)
     }
 
@@ -32,17 +96,42 @@ impl Response {
     where
         T: Serialize,
     {
-        let bytes = bincode::serialize(obj).expect("serialize object");
-        Response::Object(bytes)
+        Response::Object(serde_json::to_value(obj).expect("serialize object"))
     }
 
-    pub fn into<T>(self) -> Result<T, bincode::Error>
+    pub fn into<T>(self) -> Result<T, serde_json::Error>
     where
         T: DeserializeOwned,
     {
         match self {
-            Response::Object(bytes) => bincode::deserialize(&bytes),
-            _ => Err(bincode::ErrorKind::DeserializeAnyNotSupported.into()),
+            Response::Object(value) => serde_json::from_value(value),
+            _ => Err(serde::de::Error::custom("not an object")),
         }
     }
 }
+
+impl Request {
+    /// the agent a request claims to come from
+    pub fn agent_id(&self) -> Option<&Uuid> {
+        match self {
+            Request::PullTask { id }
+            | Request::Subscribe { id }
+            | Request::Heartbeat { id }
+            | Request::ReportStatus { id, .. } => Some(id),
+            _ => None,
+        }
+    }
+}
+
+/// numbers the requests sent by one end of a connection, so that their
+/// responses can be matched in any order
+pub type RequestId = u64;
+
+/// frames exchanged in both directions after the handshake; either end may
+/// send requests, each answered by a response with the same id
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Message {
+    Request { id: RequestId, req: Request },
+    Response { id: RequestId, resp: Response },
+}
@@ -0,0 +1,135 @@
+use crate::FrameError;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// frames older than this or this far ahead of our clock are rejected, in
+/// milliseconds
+pub const MAX_CLOCK_SKEW: u64 = 5 * 60 * 1000;
+
+/// number of sequence numbers below the highest one still accepted; a
+/// connection delivers its frames in order, so this only matters to a peer
+/// that does not
+const WINDOW_SIZE: u64 = 64;
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// sliding window over the sequence numbers received on one connection
+#[derive(Debug, Default)]
+pub struct ReplayWindow {
+    highest: u64,
+    /// bit `i` is set if `highest - i` was received
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// accept a frame with an authenticated `timestamp` and `seq` only once
+    pub fn check(&mut self, timestamp: u64, seq: u64) -> Result<(), FrameError> {
+        let now = now_millis();
+        if timestamp + MAX_CLOCK_SKEW < now || timestamp > now + MAX_CLOCK_SKEW {
+            return Err(FrameError::Stale);
+        }
+
+        if seq > self.highest {
+            let shift = seq - self.highest;
+            self.seen = if shift >= WINDOW_SIZE {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.highest = seq;
+            return Ok(());
+        }
+
+        let offset = self.highest - seq;
+        if offset >= WINDOW_SIZE || self.seen & (1 << offset) != 0 {
+            return Err(FrameError::Replayed);
+        }
+        self.seen |= 1 << offset;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(window: &mut ReplayWindow, seq: u64) -> Result<(), FrameError> {
+        window.check(now_millis(), seq)
+    }
+
+    #[test]
+    fn in_order() {
+        let mut window = ReplayWindow::default();
+        for seq in 0..200 {
+            assert!(check(&mut window, seq).is_ok());
+        }
+    }
+
+    #[test]
+    fn in_window() {
+        let mut window = ReplayWindow::default();
+        assert!(check(&mut window, 10).is_ok());
+        assert!(check(&mut window, 3).is_ok());
+        assert!(check(&mut window, 9).is_ok());
+        assert!(check(&mut window, 11).is_ok());
+    }
+
+    #[test]
+    fn duplicate() {
+        let mut window = ReplayWindow::default();
+        assert!(check(&mut window, 0).is_ok());
+        assert!(matches!(check(&mut window, 0), Err(FrameError::Replayed)));
+        assert!(check(&mut window, 10).is_ok());
+        assert!(check(&mut window, 5).is_ok());
+        assert!(matches!(check(&mut window, 5), Err(FrameError::Replayed)));
+        assert!(matches!(check(&mut window, 10), Err(FrameError::Replayed)));
+        assert!(matches!(check(&mut window, 0), Err(FrameError::Replayed)));
+    }
+
+    #[test]
+    fn out_of_window() {
+        let mut window = ReplayWindow::default();
+        assert!(check(&mut window, 100).is_ok());
+        assert!(check(&mut window, 100 - WINDOW_SIZE + 1).is_ok());
+        assert!(matches!(
+            check(&mut window, 100 - WINDOW_SIZE),
+            Err(FrameError::Replayed)
+        ));
+    }
+
+    #[test]
+    fn shift_overflow() {
+        let mut window = ReplayWindow::default();
+        assert!(check(&mut window, 1).is_ok());
+        // shifting by the window size or more clears the window instead of
+        // overflowing
+        assert!(check(&mut window, 1 + WINDOW_SIZE).is_ok());
+        assert!(check(&mut window, 2).is_ok());
+        assert!(check(&mut window, u64::MAX).is_ok());
+        assert!(check(&mut window, u64::MAX - 1).is_ok());
+        assert!(matches!(
+            check(&mut window, u64::MAX),
+            Err(FrameError::Replayed)
+        ));
+    }
+
+    #[test]
+    fn stale() {
+        let mut window = ReplayWindow::default();
+        let now = now_millis();
+        assert!(matches!(
+            window.check(now - MAX_CLOCK_SKEW - 1000, 0),
+            Err(FrameError::Stale)
+        ));
+        assert!(matches!(
+            window.check(now + MAX_CLOCK_SKEW + 1000, 0),
+            Err(FrameError::Stale)
+        ));
+        // a stale frame does not take up its sequence number
+        assert!(window.check(now, 0).is_ok());
+    }
+}
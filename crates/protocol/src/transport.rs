@@ -0,0 +1,160 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{
+    server::AllowAnyAuthenticatedClient, Certificate, ClientConfig, PrivateKey, RootCertStore,
+    ServerConfig, ServerName,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// a byte stream between agent and server, plain tcp or tls
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub type BoxStream = Box<dyn Stream>;
+
+/// tls of the control channel on the server, agents must present a
+/// certificate signed by `client_ca_file`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsServerConfig {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    pub client_ca_file: PathBuf,
+}
+
+/// tls of the control channel on the agent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsClientConfig {
+    /// ca the server certificate must be signed by
+    pub ca_file: PathBuf,
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    /// name in the server certificate, defaults to the host of the server address
+    #[serde(default)]
+    pub server_name: Option<String>,
+}
+
+fn tls_error(e: impl ToString) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, e.to_string())
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(tls_error(format!("no certificate in {}", path.display())));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(tls_error(format!("no private key in {}", path.display())))
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert).map_err(tls_error)?;
+    }
+    Ok(roots)
+}
+
+/// opens connections to the server
+pub enum Connector {
+    Plain,
+    Tls {
+        connector: TlsConnector,
+        server_name: ServerName,
+    },
+}
+
+impl Connector {
+    /// `server` is the address connected to, used as the default server name
+    pub fn new(tls: Option<&TlsClientConfig>, server: &str) -> io::Result<Self> {
+        let Some(tls) = tls else {
+            return Ok(Connector::Plain);
+        };
+
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(load_roots(&tls.ca_file)?)
+            .with_client_auth_cert(load_certs(&tls.cert_file)?, load_key(&tls.key_file)?)
+            .map_err(tls_error)?;
+
+        let name = match &tls.server_name {
+            Some(name) => name.as_str(),
+            None => server.rsplit_once(':').map_or(server, |(host, _)| host),
+        };
+        let server_name = ServerName::try_from(name.trim_matches(['[', ']'])).map_err(tls_error)?;
+
+        Ok(Connector::Tls {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+
+    pub async fn connect(&self, addr: &str) -> io::Result<BoxStream> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+
+        match self {
+            Connector::Plain => Ok(Box::new(stream)),
+            Connector::Tls {
+                connector,
+                server_name,
+            } => Ok(Box::new(
+                connector.connect(server_name.clone(), stream).await?,
+            )),
+        }
+    }
+}
+
+/// accepts connections of agents
+pub enum Acceptor {
+    Plain,
+    Tls(TlsAcceptor),
+}
+
+impl Acceptor {
+    pub fn new(tls: Option<&TlsServerConfig>) -> io::Result<Self> {
+        let Some(tls) = tls else {
+            return Ok(Acceptor::Plain);
+        };
+
+        let verifier = AllowAnyAuthenticatedClient::new(load_roots(&tls.client_ca_file)?).boxed();
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(load_certs(&tls.cert_file)?, load_key(&tls.key_file)?)
+            .map_err(tls_error)?;
+
+        Ok(Acceptor::Tls(TlsAcceptor::from(Arc::new(config))))
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Acceptor::Tls(_))
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> io::Result<BoxStream> {
+        stream.set_nodelay(true)?;
+
+        match self {
+            Acceptor::Plain => Ok(Box::new(stream)),
+            Acceptor::Tls(acceptor) => Ok(Box::new(acceptor.accept(stream).await?)),
+        }
+    }
+}
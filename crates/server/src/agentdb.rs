@@ -1,9 +1,11 @@
 use crate::config::load;
-use protocol::TaskSpec;
+use protocol::{make_key, Key, PublicKey, TaskSpec};
 use serde::Deserialize;
 use serde::Serialize;
 use std::path::Path;
+use std::time::{Duration, SystemTime};
 use std::{collections::HashMap, fs::File, io::Write, path::PathBuf};
+use tokio::sync::broadcast;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,6 +17,18 @@ pub struct Agent {
     pub pull_interval: u64,
     pub report: bool,
     pub report_interval: u64,
+    /// x25519 public key of the agent, base64
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// key replaced by the last rotation, still accepted until it expires
+    #[serde(default)]
+    pub previous_key: Option<PreviousKey>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreviousKey {
+    pub key: String,
+    pub expires: SystemTime,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -24,22 +38,57 @@ pub struct AgentData {
     pub tasks: HashMap<Uuid, TaskSpec>,
 }
 
+/// a change of the tasks of an agent
+#[derive(Debug, Clone)]
+pub enum TaskChange {
+    Upsert {
+        agent: Uuid,
+        task: Uuid,
+        spec: Box<TaskSpec>,
+    },
+    Remove {
+        agent: Uuid,
+        task: Uuid,
+    },
+    AgentRemoved {
+        agent: Uuid,
+    },
+}
+
+impl TaskChange {
+    pub fn agent(&self) -> &Uuid {
+        match self {
+            TaskChange::Upsert { agent, .. } => agent,
+            TaskChange::Remove { agent, .. } => agent,
+            TaskChange::AgentRemoved { agent } => agent,
+        }
+    }
+}
+
 pub struct AgentDb {
     file: PathBuf,
     agent: HashMap<Uuid, AgentData>,
+    changes: broadcast::Sender<TaskChange>,
 }
 
 impl AgentDb {
     pub fn new(file: impl AsRef<Path>) -> Self {
         Self {
             file: file.as_ref().to_path_buf(),
-            agent: match load(&file) {
-                Ok(v) => v,
-                Err(_) => HashMap::new(),
-            },
+            agent: load(&file).unwrap_or_default(),
+            changes: broadcast::channel(64).0,
         }
     }
 
+    pub fn subscribe(&self) -> broadcast::Receiver<TaskChange> {
+        self.changes.subscribe()
+    }
+
+    fn notify(&self, change: TaskChange) {
+        // no receiver if no session is open
+        let _ = self.changes.send(change);
+    }
+
     pub fn list_agents(&self) -> Vec<Uuid> {
         self.agent.keys().cloned().collect()
     }
@@ -64,9 +113,41 @@ impl AgentDb {
     pub fn remove(&mut self, k: &Uuid) -> Option<AgentData> {
         let res = self.agent.remove(k)?;
         self.sync();
+        self.notify(TaskChange::AgentRemoved { agent: *k });
         Some(res)
     }
 
+    /// keys an agent may currently connect with, the current one first
+    pub fn agent_keys(&self, k: &Uuid) -> Vec<Key> {
+        let Some(agent) = self.agent.get(k) else {
+            return vec![];
+        };
+        let mut keys = vec![make_key(&agent.config.key)];
+        if let Some(prev) = &agent.config.previous_key {
+            if prev.expires > SystemTime::now() {
+                keys.push(make_key(&prev.key));
+            }
+        }
+        keys
+    }
+
+    pub fn agent_public_key(&self, k: &Uuid) -> Option<PublicKey> {
+        let key = self.agent.get(k)?.config.public_key.as_ref()?;
+        protocol::parse_public_key(key)
+            .map_err(|e| log::error!("Invalid public key of agent [{}]: {}", k, e))
+            .ok()
+    }
+
+    /// replace the key of an agent, keeping the old one valid for `overlap`
+    pub fn rotate_key(&mut self, k: &Uuid, key: String, overlap: Duration) -> Option<SystemTime> {
+        let agent = self.agent.get_mut(k)?;
+        let expires = SystemTime::now() + overlap;
+        let old = std::mem::replace(&mut agent.config.key, key);
+        agent.config.previous_key = Some(PreviousKey { key: old, expires });
+        self.sync();
+        Some(expires)
+    }
+
     pub fn get_agent(&self, k: &Uuid) -> Option<&AgentData> {
         self.agent.get(k)
     }
@@ -74,8 +155,13 @@ impl AgentDb {
     pub fn insert_agent_task(&mut self, k: &Uuid, v: TaskSpec) -> Option<Uuid> {
         let agent = self.agent.get_mut(k)?;
         let id = Uuid::new_v4();
-        agent.tasks.insert(id, v);
+        agent.tasks.insert(id, v.clone());
         self.sync();
+        self.notify(TaskChange::Upsert {
+            agent: *k,
+            task: id,
+            spec: Box::new(v),
+        });
         Some(id)
     }
 
@@ -86,8 +172,13 @@ impl AgentDb {
         }
 
         let task = agent.tasks.get_mut(tk).unwrap();
-        *task = v;
+        *task = v.clone();
         self.sync();
+        self.notify(TaskChange::Upsert {
+            agent: *ak,
+            task: *tk,
+            spec: Box::new(v),
+        });
         Some(())
     }
 
@@ -95,6 +186,10 @@ impl AgentDb {
         let agent = self.agent.get_mut(ak)?;
         let task = agent.tasks.remove(tk)?;
         self.sync();
+        self.notify(TaskChange::Remove {
+            agent: *ak,
+            task: *tk,
+        });
         Some(task)
     }
 
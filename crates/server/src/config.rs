@@ -1,3 +1,4 @@
+use protocol::{FrameLimits, TlsServerConfig};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::{error::Error, fs, io, path::Path};
@@ -6,7 +7,35 @@ use std::{error::Error, fs, io, path::Path};
 pub struct Config {
     pub ctl_addr: String,
     pub api_addr: String,
-    pub key: String,
+    /// x25519 private key of the server, base64
+    pub private_key: String,
+    /// serve the control channel over tls
+    #[serde(default)]
+    pub tls: Option<TlsServerConfig>,
+    /// seconds without heartbeat before an agent session is closed
+    #[serde(default = "default_session_timeout")]
+    pub session_timeout: u64,
+    /// seconds an agent's previous key stays valid after a rotation
+    #[serde(default = "default_key_overlap")]
+    pub key_overlap: u64,
+    /// `max_frame_size` and `max_message_size` of the control channel
+    #[serde(flatten)]
+    pub limits: FrameLimits,
+    /// frames smaller than this are sent uncompressed, in bytes
+    #[serde(default = "default_compression_threshold")]
+    pub compression_threshold: usize,
+}
+
+fn default_session_timeout() -> u64 {
+    120
+}
+
+fn default_key_overlap() -> u64 {
+    24 * 3600
+}
+
+fn default_compression_threshold() -> usize {
+    256
 }
 
 pub fn load<T: DeserializeOwned, P: AsRef<Path>>(path: P) -> Result<T, Box<dyn Error>> {
@@ -16,9 +45,10 @@ pub fn load<T: DeserializeOwned, P: AsRef<Path>>(path: P) -> Result<T, Box<dyn E
     Ok(u)
 }
 
+#[allow(dead_code)]
 pub fn dump<P: AsRef<Path>>(cfg: &impl Serialize, path: P) -> Result<(), Box<dyn Error>> {
     let file = fs::File::create(path)?;
     let writer = io::BufWriter::new(file);
-    let u = serde_json::to_writer(writer, cfg)?;
-    Ok(u)
+    serde_json::to_writer(writer, cfg)?;
+    Ok(())
 }
@@ -0,0 +1,150 @@
+use futures::{SinkExt, StreamExt};
+use protocol::{
+    BoxStream, Compression, FrameCodec, FrameError, FrameLimits, Greeting, PublicKey, SessionKeys,
+    Verdict,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::codec::Framed;
+use uuid::Uuid;
+
+/// a connection to an agent, framed with the keys negotiated with it
+pub struct AgentConn {
+    framed: Framed<BoxStream, FrameCodec>,
+    client: SocketAddr,
+    agent: Option<Uuid>,
+}
+
+impl AgentConn {
+    pub fn new(stream: BoxStream, client: SocketAddr, limits: FrameLimits) -> Self {
+        Self {
+            framed: Framed::new(stream, FrameCodec::new(limits)),
+            client,
+            agent: None,
+        }
+    }
+
+    pub fn client(&self) -> SocketAddr {
+        self.client
+    }
+
+    pub fn set_compression(&mut self, compression: Compression, threshold: usize) {
+        self.framed
+            .codec_mut()
+            .set_compression(compression, threshold);
+    }
+
+    fn frame_error(&self, e: FrameError) -> io::Error {
+        match &e {
+            e if e.is_security() => {
+                log::warn!(
+                    "Security: rejected frame of agent [{}] from {}: {}",
+                    self.agent.unwrap_or_default(),
+                    self.client,
+                    e
+                );
+            }
+            FrameError::Io(_) => {}
+            e => log::error!("invalid data from client {}: {}", self.client, e),
+        }
+        e.into()
+    }
+
+    /// read the greeting and the agent id sent in clear ahead of the key
+    /// exchange
+    pub async fn recv_greeting(&mut self) -> io::Result<Option<(Greeting, Uuid)>> {
+        let mut buf = [0u8; protocol::GREETING_LEN + protocol::AGENT_ID_LEN];
+        match self.framed.get_mut().read_exact(&mut buf).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                log::info!("Agent connection closed: {}", self.client);
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        }
+
+        let (greeting, id) = buf.split_at(protocol::GREETING_LEN);
+        let greeting = greeting.try_into().expect("split at greeting length");
+        let Some(greeting) = Greeting::from_bytes(greeting) else {
+            log::warn!(
+                "Client {} sent no greeting, not an agent or older than protocol version {}",
+                self.client,
+                protocol::MIN_PROTOCOL_VERSION
+            );
+            return Err(ErrorKind::InvalidData.into());
+        };
+        let id = Uuid::from_slice(id).map_err(|_| io::Error::from(ErrorKind::InvalidData))?;
+        self.agent = Some(id);
+        Ok(Some((greeting, id)))
+    }
+
+    /// read the ephemeral public key the agent sends after its greeting
+    pub async fn recv_public_key(&mut self) -> io::Result<PublicKey> {
+        let mut buf = [0u8; protocol::PUBLIC_KEY_LEN];
+        self.framed.get_mut().read_exact(&mut buf).await?;
+        Ok(PublicKey::from(buf))
+    }
+
+    /// answer the greeting with ours and the verdict, followed by our
+    /// ephemeral public key if accepted
+    pub async fn send_verdict(
+        &mut self,
+        verdict: Verdict,
+        key: Option<&PublicKey>,
+    ) -> io::Result<()> {
+        let stream = self.framed.get_mut();
+        stream.write_all(&Greeting::ours().to_bytes()).await?;
+        stream.write_all(&[verdict as u8]).await?;
+        if let Some(key) = key {
+            stream.write_all(key.as_bytes()).await?;
+        }
+        stream.flush().await
+    }
+
+    /// wait for the first frame, encrypted with any of the candidate `keys`;
+    /// the matching keys are used for the rest of the connection, their index
+    /// is returned
+    pub async fn accept<T: DeserializeOwned>(
+        &mut self,
+        keys: Vec<SessionKeys>,
+    ) -> io::Result<Option<(T, usize)>> {
+        self.framed.codec_mut().set_keys(keys);
+        let frame = match self.framed.next().await {
+            Some(Ok(frame)) => frame,
+            Some(Err(FrameError::Unauthenticated)) => {
+                log::warn!(
+                    "Security: agent [{}] failed to authenticate from: {}",
+                    self.agent.unwrap_or_default(),
+                    self.client
+                );
+                return Err(ErrorKind::PermissionDenied.into());
+            }
+            Some(Err(e)) => return Err(self.frame_error(e)),
+            None => {
+                log::info!("Agent connection closed: {}", self.client);
+                return Ok(None);
+            }
+        };
+        let msg = frame.parse().map_err(|e| self.frame_error(e))?;
+        let key = self.framed.codec().matched().expect("keys matched");
+        Ok(Some((msg, key)))
+    }
+
+    /// wait for the next frame, None if the connection is closed
+    pub async fn recv<T: DeserializeOwned>(&mut self) -> io::Result<Option<T>> {
+        let res = match self.framed.next().await {
+            Some(frame) => frame.and_then(|frame| frame.parse()),
+            None => {
+                log::info!("Agent connection closed: {}", self.client);
+                return Ok(None);
+            }
+        };
+        res.map(Some).map_err(|e| self.frame_error(e))
+    }
+
+    pub async fn send(&mut self, msg: &impl Serialize) -> io::Result<()> {
+        self.framed.send(msg).await.map_err(|e| self.frame_error(e))
+    }
+}
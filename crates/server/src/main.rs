@@ -1,29 +1,117 @@
 mod agentdb;
 mod config;
+mod conn;
 mod webapi;
-use agentdb::AgentDb;
+use agentdb::{AgentDb, TaskChange};
 use bytes::BytesMut;
 use clap::Parser;
 use config::Config;
+use conn::AgentConn;
 use log::LevelFilter;
-use protocol::{make_key, DecodeError, Key, Request, Response};
+use protocol::{
+    Acceptor, BoxStream, Capabilities, Change, Compression, Event, EventType, FrameLimits,
+    Greeting, Hello, Message, PublicKey, Request, RequestId, Response, ResponseError, StaticSecret,
+    TaskError, TaskSpec, Verdict, Welcome,
+};
+use serde::Serialize;
+use std::collections::HashMap;
 use std::error::Error;
-use std::io::{self, ErrorKind};
+use std::io;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use std::{net::SocketAddr, sync::Arc};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufWriter},
-    net::TcpStream,
-    sync::RwLock,
+    io::AsyncWriteExt,
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc, oneshot, RwLock,
+    },
+    time::Instant,
 };
 use uuid::Uuid;
 use webapi::WebApi;
 
+/// seconds to wait for an agent to answer a request sent through its session
+const AGENT_CALL_TIMEOUT: u64 = 30;
+
+/// requests of one connection handled at the same time, more are answered
+/// with `Busy`
+const MAX_IN_FLIGHT: usize = 16;
+
+/// seconds an agent is asked to wait when the server is busy
+const BUSY_RETRY_AFTER: u64 = 5;
+
+/// a request to an agent and where to deliver its response
+type SessionCall = (Request, oneshot::Sender<Response>);
+
+/// what an agent told about itself in its last handshake
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentInfo {
+    pub addr: String,
+    pub agent_version: String,
+    /// negotiated version, None if the agent was refused
+    pub protocol_version: Option<u16>,
+    pub capabilities: Capabilities,
+    pub last_seen: SystemTime,
+}
+
+/// a line of the per-task log files; the exit status and the captured output
+/// of a run are fields of their own rather than nested in its result
+#[derive(Debug, Serialize)]
+struct LogEntry<'a> {
+    id: Uuid,
+    type_: &'a EventType,
+    start: SystemTime,
+    end: SystemTime,
+    attempt: Option<u8>,
+    parent: Option<Uuid>,
+    status: Option<i32>,
+    message: &'a str,
+    change: Option<&'a Change>,
+    stdout: &'a str,
+    stderr: &'a str,
+    /// why the run failed, None if it completed
+    error: Option<&'a TaskError>,
+}
+
+impl<'a> From<&'a Event> for LogEntry<'a> {
+    fn from(event: &'a Event) -> Self {
+        let (result, error) = match &event.result {
+            Ok(result) => (Some(result), None),
+            Err(e) => (None, Some(e)),
+        };
+        LogEntry {
+            id: event.id,
+            type_: &event.type_,
+            start: event.start,
+            end: event.end,
+            attempt: event.attempt,
+            parent: event.parent,
+            status: result.and_then(|r| r.status),
+            message: result.map_or("", |r| &r.message),
+            change: result.and_then(|r| r.change.as_ref()),
+            stdout: result.map_or("", |r| &r.stdout),
+            stderr: result.map_or("", |r| &r.stderr),
+            error,
+        }
+    }
+}
+
 pub struct Server {
     ctl_addr: String,
     api_addr: String,
-    aes_key: Key,
+    acceptor: Acceptor,
+    /// static key the agents pin the public part of
+    secret: StaticSecret,
+    session_timeout: u64,
+    key_overlap: Duration,
+    limits: FrameLimits,
+    compression_threshold: usize,
     agents: RwLock<AgentDb>,
+    /// agents with an open session
+    sessions: RwLock<HashMap<Uuid, mpsc::Sender<SessionCall>>>,
+    agent_info: RwLock<HashMap<Uuid, AgentInfo>>,
+    runtime: tokio::runtime::Handle,
     logs_dir: PathBuf,
 }
 
@@ -32,8 +120,17 @@ impl Server {
         Self {
             ctl_addr: config.ctl_addr,
             api_addr: config.api_addr,
-            aes_key: make_key(&config.key),
+            acceptor: Acceptor::new(config.tls.as_ref()).expect("invalid tls config"),
+            secret: protocol::parse_secret_key(&config.private_key)
+                .expect("invalid private_key in config"),
+            session_timeout: config.session_timeout,
+            key_overlap: Duration::from_secs(config.key_overlap),
+            limits: config.limits,
+            compression_threshold: config.compression_threshold,
             agents: RwLock::new(AgentDb::new(agentdb_path)),
+            sessions: RwLock::new(HashMap::new()),
+            agent_info: RwLock::new(HashMap::new()),
+            runtime: tokio::runtime::Handle::current(),
             logs_dir,
         }
     }
@@ -57,15 +154,29 @@ impl Server {
         let listener = tokio::net::TcpListener::bind(self.ctl_addr.as_str())
             .await
             .expect("Failed to bind");
-        log::info!("Controller listening on {}", self.ctl_addr);
+        let transport = if self.acceptor.is_tls() {
+            "tls"
+        } else {
+            "plain"
+        };
+        log::info!("Controller listening on {} ({})", self.ctl_addr, transport);
+        log::info!(
+            "Server public key: {}",
+            protocol::encode_public_key(&PublicKey::from(&self.secret))
+        );
 
         while let Ok((stream, client)) = listener.accept().await {
             log::info!("New agent connection from: {}", client);
 
-            stream.set_nodelay(true).expect("Failed to set nodelay");
-
             let me = self.clone();
             tokio::spawn(async move {
+                let stream = match me.acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::warn!("Failed to accept connection from {}: {}", client, e);
+                        return;
+                    }
+                };
                 let _ = me.handle_agent(stream, client).await;
             });
         }
@@ -73,42 +184,349 @@ impl Server {
 
     async fn handle_agent(
         self: &Arc<Self>,
-        stream: TcpStream,
+        stream: BoxStream,
         client: SocketAddr,
     ) -> io::Result<()> {
-        let mut wfile = BufWriter::new(stream);
-        let mut buf = BytesMut::new();
-        buf.reserve(1024);
+        let mut conn = AgentConn::new(stream, client, self.limits);
 
-        loop {
-            let n = wfile.read_buf(&mut buf).await?;
-            if n == 0 {
-                log::info!("Agent connection closed: {}", client);
+        let Some((greeting, id)) = conn.recv_greeting().await? else {
+            return Ok(());
+        };
+        let version = match greeting.negotiate() {
+            Ok(version) => version,
+            Err(e) => {
+                log::warn!("Agent [{}] refused: {}", id, e);
+                self.refused(id, client).await;
+                return conn.send_verdict(Verdict::UnsupportedVersion, None).await;
+            }
+        };
+        let agent_ephemeral = conn.recv_public_key().await?;
+
+        let (psks, agent_public) = {
+            let agents = self.agents.read().await;
+            (agents.agent_keys(&id), agents.agent_public_key(&id))
+        };
+        if psks.is_empty() {
+            log::warn!("Unknown agent [{}] from: {}", id, client);
+            return conn.send_verdict(Verdict::UnknownAgent, None).await;
+        }
+        let Some(agent_public) = agent_public else {
+            log::warn!(
+                "Agent [{}] from {} has no valid public key registered, set its public_key in the agent db",
+                id,
+                client
+            );
+            // older agents do not know the verdict
+            let verdict = if version >= 15 {
+                Verdict::KeyNotRegistered
+            } else {
+                Verdict::UnknownAgent
+            };
+            return conn.send_verdict(verdict, None).await;
+        };
+
+        let ephemeral = protocol::generate_secret();
+        conn.send_verdict(Verdict::Accepted, Some(&PublicKey::from(&ephemeral)))
+            .await?;
+
+        // one candidate per key the agent may still use
+        let mut keys = vec![];
+        for psk in &psks {
+            let Some(k) = protocol::server_session_keys(
+                &id,
+                &self.secret,
+                &ephemeral,
+                &agent_public,
+                &agent_ephemeral,
+                psk,
+            ) else {
+                log::warn!("Security: agent [{}] sent a weak key from: {}", id, client);
                 return Ok(());
+            };
+            keys.push(k);
+        }
+
+        let Some((hello, key)) = conn.accept::<Hello>(keys).await? else {
+            return Ok(());
+        };
+        if hello.agent_id != id {
+            log::warn!(
+                "Agent [{}] sent a handshake for another agent: {}",
+                id,
+                client
+            );
+            return Ok(());
+        }
+        if key > 0 {
+            log::warn!("Agent [{}] still uses its previous key", id);
+        }
+
+        let compression = Compression::negotiate(&hello.capabilities.compression);
+        conn.set_compression(compression, self.compression_threshold);
+        log::debug!("Agent [{}] uses {} compression", id, compression.name());
+
+        let welcome = self.handshake(&hello, greeting, version, client).await;
+        conn.send(&welcome).await?;
+        if welcome.is_err() {
+            return Ok(());
+        }
+
+        let mut registered = None;
+        let res = self.serve(conn, &hello, version, &mut registered).await;
+
+        // a newer session of the same agent may have replaced this one
+        if let Some(calls_tx) = registered {
+            let mut sessions = self.sessions.write().await;
+            if sessions.get(&id).is_some_and(|s| s.same_channel(&calls_tx)) {
+                sessions.remove(&id);
             }
+        }
+        res
+    }
 
-            while buf.len() > protocol::HEADER_LEN {
-                let req = protocol::decode(&mut buf, &self.aes_key);
-                let req = match req {
-                    Ok(msg) => msg,
-                    Err(DecodeError::NotEnoughData) => {
-                        break;
-                    }
-                    Err(DecodeError::InvalidData) => {
-                        log::error!("invalid data from client: {}", client);
-                        return Err(ErrorKind::InvalidData.into());
+    /// check the greeting against the authenticated hello and remember what
+    /// the agent told us
+    async fn handshake(
+        &self,
+        hello: &Hello,
+        greeting: Greeting,
+        version: u16,
+        client: SocketAddr,
+    ) -> Result<Welcome, String> {
+        let version = if hello.greeting() == greeting {
+            Ok(version)
+        } else {
+            log::warn!(
+                "Security: agent [{}] greeted with other versions than it said hello with: {}",
+                hello.agent_id,
+                client
+            );
+            Err("versions differ from the greeting".to_string())
+        };
+
+        let info = AgentInfo {
+            addr: client.to_string(),
+            agent_version: hello.agent_version.clone(),
+            protocol_version: version.as_ref().ok().copied(),
+            capabilities: hello.capabilities.clone(),
+            last_seen: SystemTime::now(),
+        };
+        self.agent_info.write().await.insert(hello.agent_id, info);
+
+        Ok(Welcome {
+            protocol_version: version?,
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+        })
+    }
+
+    /// remember that an agent was refused, along with what it told us before
+    async fn refused(&self, id: Uuid, client: SocketAddr) {
+        let mut agent_info = self.agent_info.write().await;
+        let info = agent_info.entry(id).or_insert_with(|| AgentInfo {
+            addr: String::new(),
+            agent_version: String::new(),
+            protocol_version: None,
+            capabilities: Capabilities::default(),
+            last_seen: SystemTime::now(),
+        });
+        info.addr = client.to_string();
+        info.protocol_version = None;
+        info.last_seen = SystemTime::now();
+    }
+
+    /// tasks of an agent it is able to run
+    fn supported_tasks(
+        id: &Uuid,
+        tasks: &HashMap<Uuid, TaskSpec>,
+        caps: &Capabilities,
+        version: u16,
+    ) -> HashMap<Uuid, TaskSpec> {
+        tasks
+            .iter()
+            .filter(|(tid, spec)| {
+                let supported = caps.supports(spec, version);
+                if !supported {
+                    log::warn!("Agent [{}] does not support task [{}], skip it", id, tid);
+                }
+                supported
+            })
+            .map(|(tid, spec)| (*tid, spec.clone()))
+            .collect()
+    }
+
+    /// send a request to an agent through its session and wait for the
+    /// response, None if the agent is not connected or does not answer
+    pub async fn call_agent(&self, id: &Uuid, req: Request) -> Option<Response> {
+        let session = self.sessions.read().await.get(id)?.clone();
+        let (tx, rx) = oneshot::channel();
+        session.send((req, tx)).await.ok()?;
+
+        // the web api runs outside of tokio, so wait on its timer there
+        let wait =
+            async move { tokio::time::timeout(Duration::from_secs(AGENT_CALL_TIMEOUT), rx).await };
+        self.runtime.spawn(wait).await.ok()?.ok()?.ok()
+    }
+
+    /// Answer the requests of an agent until it goes silent.
+    ///
+    /// Requests are handled in their own task so that a slow one does not
+    /// hold up the others, their responses are matched by id. Once the agent
+    /// subscribed, its task changes and requests from the web api are pushed
+    /// to it as well; `registered` is set to the channel of the session.
+    async fn serve(
+        self: &Arc<Self>,
+        mut conn: AgentConn,
+        hello: &Hello,
+        version: u16,
+        registered: &mut Option<mpsc::Sender<SessionCall>>,
+    ) -> io::Result<()> {
+        let (id, caps) = (hello.agent_id, &hello.capabilities);
+        let (done_tx, mut done) = mpsc::channel::<(RequestId, Response)>(MAX_IN_FLIGHT);
+        let mut in_flight = 0;
+        // requests we sent, None for pushed task changes
+        let mut pending: HashMap<RequestId, Option<oneshot::Sender<Response>>> = HashMap::new();
+        let mut next_id: RequestId = 0;
+
+        // id of the subscription, and what is pushed once subscribed
+        let mut subscription: Option<RequestId> = None;
+        let mut changes: Option<broadcast::Receiver<TaskChange>> = None;
+        let mut calls: Option<mpsc::Receiver<SessionCall>> = None;
+
+        let timeout = Duration::from_secs(self.session_timeout);
+        let mut deadline = Instant::now() + timeout;
+        loop {
+            tokio::select! {
+                msg = conn.recv::<Message>() => {
+                    let Some(msg) = msg? else {
+                        return Ok(());
+                    };
+                    deadline = Instant::now() + timeout;
+
+                    let (rid, req) = match msg {
+                        Message::Request { id: rid, req } => (rid, req),
+                        Message::Response { id: rid, resp } => {
+                            match pending.remove(&rid) {
+                                Some(Some(tx)) => {
+                                    let _ = tx.send(resp);
+                                }
+                                Some(None) => {
+                                    if let Response::Error(e) = resp {
+                                        log::error!("Agent [{}] failed to apply change: {}", id, e);
+                                    }
+                                }
+                                None => log::warn!("Unexpected response from agent [{}]", id),
+                            }
+                            continue;
+                        }
+                    };
+
+                    if req.agent_id().is_some_and(|aid| aid != &id) {
+                        log::warn!(
+                            "Agent [{}] sent a request for another agent: {}",
+                            id,
+                            conn.client()
+                        );
+                        let resp = Response::err(ResponseError::Unauthorized(
+                            "agent id mismatch".into(),
+                        ));
+                        conn.send(&Message::Response { id: rid, resp }).await?;
+                        continue;
                     }
-                };
 
-                let resp = match self.handle_request(req).await {
-                    Ok(resp) => resp,
-                    Err(e) => Response::err(e.to_string()),
-                };
+                    match req {
+                        Request::Heartbeat { .. } => {
+                            let resp = Response::ok();
+                            conn.send(&Message::Response { id: rid, resp }).await?;
+                        }
+                        Request::Subscribe { .. } => {
+                            // subscribe first so that no change after the task list is missed
+                            let rx = self.agents.read().await.subscribe();
+                            let tasks = self
+                                .agents
+                                .read()
+                                .await
+                                .get_agent(&id)
+                                .map(|agent| Self::supported_tasks(&id, &agent.tasks, caps, version));
+                            let Some(tasks) = tasks else {
+                                log::warn!("Agent not found: [{}]", id);
+                                let resp = Response::err(ResponseError::UnknownAgent);
+                                return conn.send(&Message::Response { id: rid, resp }).await;
+                            };
+                            let resp = Response::object(&tasks);
+                            conn.send(&Message::Response { id: rid, resp }).await?;
 
-                let mut wbuf = BytesMut::new();
-                protocol::encode(&resp, &mut wbuf, &self.aes_key);
-                wfile.write_all(&wbuf).await?;
-                wfile.flush().await?;
+                            changes = Some(rx);
+                            if subscription.replace(rid).is_none() {
+                                let (calls_tx, calls_rx) = mpsc::channel(16);
+                                self.sessions.write().await.insert(id, calls_tx.clone());
+                                *registered = Some(calls_tx);
+                                calls = Some(calls_rx);
+                                log::info!("Agent [{}] subscribed from: {}", id, conn.client());
+                            }
+                        }
+                        _ if in_flight >= MAX_IN_FLIGHT => {
+                            log::warn!("Agent [{}] has too many requests in flight", id);
+                            let resp = Response::err(ResponseError::Busy(BUSY_RETRY_AFTER));
+                            conn.send(&Message::Response { id: rid, resp }).await?;
+                        }
+                        req => {
+                            in_flight += 1;
+                            let me = self.clone();
+                            let caps = caps.clone();
+                            let done_tx = done_tx.clone();
+                            tokio::spawn(async move {
+                                let resp = match me.handle_request(req, &caps, version).await {
+                                    Ok(resp) => resp,
+                                    Err(e) => Response::err(ResponseError::Internal(e.to_string())),
+                                };
+                                let _ = done_tx.send((rid, resp)).await;
+                            });
+                        }
+                    }
+                }
+                Some((rid, resp)) = done.recv() => {
+                    in_flight -= 1;
+                    conn.send(&Message::Response { id: rid, resp }).await?;
+                }
+                change = next_change(&mut changes) => {
+                    let req = match change {
+                        Ok(change) if change.agent() != &id => continue,
+                        Ok(TaskChange::Upsert { task, spec, .. }) if caps.supports(&spec, version) => {
+                            Request::AddTask { id: task, spec: *spec }
+                        }
+                        // make sure an older version of the task does not linger
+                        Ok(TaskChange::Upsert { task, .. }) => {
+                            log::warn!("Agent [{}] does not support task [{}], remove it", id, task);
+                            Request::RemoveTask { id: task }
+                        }
+                        Ok(TaskChange::Remove { task, .. }) => Request::RemoveTask { id: task },
+                        // answer the subscription again, ending it
+                        Ok(TaskChange::AgentRemoved { .. }) => {
+                            log::info!("Agent [{}] removed, close session", id);
+                            let resp = Response::err(ResponseError::UnknownAgent);
+                            let rid = subscription.unwrap_or_default();
+                            return conn.send(&Message::Response { id: rid, resp }).await;
+                        }
+                        // the agent resyncs all tasks when it reconnects
+                        Err(RecvError::Lagged(_)) => {
+                            log::warn!("Agent [{}] session lagged behind, close it", id);
+                            return Ok(());
+                        }
+                        Err(RecvError::Closed) => return Ok(()),
+                    };
+                    next_id += 1;
+                    conn.send(&Message::Request { id: next_id, req }).await?;
+                    pending.insert(next_id, None);
+                }
+                Some((req, tx)) = next_call(&mut calls) => {
+                    next_id += 1;
+                    conn.send(&Message::Request { id: next_id, req }).await?;
+                    pending.insert(next_id, Some(tx));
+                }
+                _ = tokio::time::sleep_until(deadline) => {
+                    log::warn!("Agent [{}] connection timeout: {}", id, conn.client());
+                    return Ok(());
+                }
             }
         }
     }
@@ -116,26 +534,42 @@ impl Server {
     async fn handle_request(
         self: &Arc<Self>,
         req: protocol::Request,
+        caps: &Capabilities,
+        version: u16,
     ) -> Result<protocol::Response, Box<dyn Error + Send + Sync>> {
         match req {
             Request::PullTask { id } => {
                 if let Some(v) = self.agents.read().await.get_agent(&id).map(|a| &a.tasks) {
-                    Ok(Response::object(v))
+                    Ok(Response::object(&Self::supported_tasks(
+                        &id, v, caps, version,
+                    )))
                 } else {
                     log::warn!("Agent not found: [{}]", id);
-                    Ok(Response::err("Agent not found".into()))
+                    Ok(Response::err(ResponseError::UnknownAgent))
                 }
             }
-            Request::ReportStatus { id, log } => {
-                // to json
+            Request::Heartbeat { .. } => Ok(Response::ok()),
+            Request::ReportStatus { id, log, dropped } => {
+                if dropped > 0 {
+                    log::warn!("Agent [{}] dropped {} events", id, dropped);
+                }
+
+                // only acknowledged once persisted, the agent keeps the events
+                // and reports them again otherwise
                 if let Err(e) = self.persist_log(id, log).await {
                     log::error!("Failed to persist log: {}", e);
+                    return Ok(Response::err(ResponseError::Internal(format!(
+                        "failed to persist log: {}",
+                        e
+                    ))));
                 }
                 Ok(Response::ok())
             }
             _ => {
                 log::error!("Unhandled request: {:?}", req);
-                Ok(Response::err("Unhandled request".to_string()))
+                Ok(Response::err(ResponseError::Unsupported(
+                    "unhandled request".to_string(),
+                )))
             }
         }
     }
@@ -145,18 +579,18 @@ impl Server {
         for (tid, log) in log {
             // create parent dir
             let logs_dir = self.logs_dir.join(id.to_string());
-            tokio::fs::create_dir_all(logs_dir).await?;
+            tokio::fs::create_dir_all(&logs_dir).await?;
 
             let mut file = tokio::fs::OpenOptions::new()
                 .create(true)
                 .append(true)
-                .open(format!("logs/{}/{}.json", id, tid))
+                .open(logs_dir.join(format!("{}.json", tid)))
                 .await?;
 
             // write each log as a json object in a line
             let mut wbuf = BytesMut::new();
-            for l in log {
-                let s = serde_json::to_string(&l)?;
+            for l in &log {
+                let s = serde_json::to_string(&LogEntry::from(l))?;
                 wbuf.extend_from_slice(s.as_bytes());
                 wbuf.extend_from_slice(b"\r\n");
             }
@@ -169,6 +603,24 @@ impl Server {
     }
 }
 
+/// the next task change of a subscribed agent, never before it subscribed
+async fn next_change(
+    changes: &mut Option<broadcast::Receiver<TaskChange>>,
+) -> Result<TaskChange, RecvError> {
+    match changes {
+        Some(changes) => changes.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// the next web api request to a subscribed agent
+async fn next_call(calls: &mut Option<mpsc::Receiver<SessionCall>>) -> Option<SessionCall> {
+    match calls {
+        Some(calls) => calls.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(name = "server")]
 #[command(author = "frezcirno")]
@@ -176,8 +628,8 @@ impl Server {
 #[command(about = "A Centralized Cron-like Task Manager - Server End", long_about = None)]
 struct Args {
     /// Server config file
-    #[arg(short, long, value_name = "FILE")]
-    config: PathBuf,
+    #[arg(short, long, value_name = "FILE", required_unless_present = "keygen")]
+    config: Option<PathBuf>,
 
     /// Agent data file
     #[arg(short, long, value_name = "FILE")]
@@ -186,6 +638,10 @@ struct Args {
     /// Logs path
     #[arg(short, long, value_name = "FILE")]
     logs_dir: Option<PathBuf>,
+
+    /// Print a new x25519 key pair and exit
+    #[arg(long)]
+    keygen: bool,
 }
 
 #[tokio::main]
@@ -194,7 +650,18 @@ async fn main() {
 
     let args = Args::parse();
 
-    let config = config::load(&args.config).expect("load config failed");
+    if args.keygen {
+        let secret = protocol::generate_secret();
+        println!("private_key: {}", protocol::encode_secret_key(&secret));
+        println!(
+            "public_key: {}",
+            protocol::encode_public_key(&protocol::PublicKey::from(&secret))
+        );
+        return;
+    }
+
+    let config_path = args.config.expect("config is required");
+    let config = config::load(config_path).expect("load config failed");
 
     let agentdb_path = args.agentdb_path.unwrap_or(
         dirs::data_dir()
@@ -1,7 +1,12 @@
 use crate::agentdb::Agent;
 use crate::Server;
 use http_types::headers::HeaderValue;
+use protocol::{
+    AgentTaskStatus, Request as AgentRequest, Response as AgentResponse, ResponseError, TaskResult,
+};
+use rand::{distributions::Alphanumeric, Rng};
 use std::sync::Arc;
+use std::time::Duration;
 use tide::security::{CorsMiddleware, Origin};
 use tide::{prelude::*, Body, Error, Request, StatusCode};
 use uuid::Uuid;
@@ -28,6 +33,15 @@ impl WebApi {
             .put(Self::put_agent_config)
             .delete(Self::delete_agent);
 
+        app.at("/agent/:agent_id/info").get(Self::get_agent_info);
+
+        app.at("/agent/:agent_id/key").post(Self::rotate_agent_key);
+
+        app.at("/agent/:agent_id/status")
+            .get(Self::get_agent_status);
+
+        app.at("/agent/:agent_id/reload").post(Self::reload_agent);
+
         app.at("/agent/:agent_id/task")
             .get(Self::list_agent_tasks)
             .post(Self::create_agent_task);
@@ -37,6 +51,9 @@ impl WebApi {
             .put(Self::put_agent_task)
             .delete(Self::delete_agent_task);
 
+        app.at("/agent/:agent_id/task/:task_id/rollback")
+            .post(Self::rollback_agent_task);
+
         app.listen(&ctx.api_addr).await.expect("Failed to bind");
     }
 
@@ -58,6 +75,62 @@ impl WebApi {
         Ok(Body::from_json(&agent.config)?.into())
     }
 
+    async fn get_agent_info(req: Request<Arc<Server>>) -> tide::Result {
+        let agent_id = Self::get_param(&req, "agent_id")?;
+        let agent_id = Uuid::parse_str(agent_id).status(StatusCode::BadRequest)?;
+
+        let agent_info = req.state().agent_info.read().await;
+        let info = agent_info.get(&agent_id).status(StatusCode::NotFound)?;
+
+        Ok(Body::from_json(info)?.into())
+    }
+
+    async fn rotate_agent_key(mut req: Request<Arc<Server>>) -> tide::Result {
+        let agent_id = Self::get_param(&req, "agent_id")?;
+        let agent_id = Uuid::parse_str(agent_id).status(StatusCode::BadRequest)?;
+
+        #[derive(Deserialize)]
+        struct Rotate {
+            key: Option<String>,
+            /// seconds the previous key stays valid
+            overlap: Option<u64>,
+        }
+        let body = req.body_string().await?;
+        let rotate: Rotate = match body.trim() {
+            "" => Rotate {
+                key: None,
+                overlap: None,
+            },
+            body => serde_json::from_str(body).status(StatusCode::BadRequest)?,
+        };
+
+        let key = rotate.key.unwrap_or_else(|| {
+            rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(43)
+                .map(char::from)
+                .collect()
+        });
+        let overlap = rotate
+            .overlap
+            .map_or(req.state().key_overlap, Duration::from_secs);
+
+        let expires = req
+            .state()
+            .agents
+            .write()
+            .await
+            .rotate_key(&agent_id, key.clone(), overlap)
+            .status(StatusCode::NotFound)?;
+        log::info!("Agent [{}] key rotated", agent_id);
+
+        Ok(Body::from_json(&json!({
+            "key": key,
+            "previous_key_expires": expires,
+        }))?
+        .into())
+    }
+
     async fn list_agent(req: Request<Arc<Server>>) -> tide::Result {
         let agent_id = req.state().agents.read().await.list_agents();
 
@@ -101,6 +174,59 @@ impl WebApi {
         Ok(StatusCode::Ok.into())
     }
 
+    /// call an agent through its session
+    async fn call_agent(
+        req: &Request<Arc<Server>>,
+        agent_req: AgentRequest,
+    ) -> tide::Result<AgentResponse> {
+        let agent_id = Self::get_param(req, "agent_id")?;
+        let agent_id = Uuid::parse_str(agent_id).status(StatusCode::BadRequest)?;
+
+        let resp = req
+            .state()
+            .call_agent(&agent_id, agent_req)
+            .await
+            .ok_or_else(|| {
+                Error::from_str(StatusCode::ServiceUnavailable, "agent is not connected")
+            })?;
+
+        let AgentResponse::Error(e) = resp else {
+            return Ok(resp);
+        };
+        let status = match e {
+            ResponseError::UnknownAgent | ResponseError::NotFound(_) => StatusCode::NotFound,
+            ResponseError::Unauthorized(_) => StatusCode::Forbidden,
+            ResponseError::Unsupported(_) => StatusCode::NotImplemented,
+            ResponseError::RateLimited(_) => StatusCode::TooManyRequests,
+            ResponseError::Busy(_) => StatusCode::ServiceUnavailable,
+            ResponseError::Internal(_) => StatusCode::InternalServerError,
+        };
+        Err(Error::from_str(status, e))
+    }
+
+    async fn get_agent_status(req: Request<Arc<Server>>) -> tide::Result {
+        let resp = Self::call_agent(&req, AgentRequest::ListTask).await?;
+        let status: AgentTaskStatus = resp.into().status(StatusCode::BadGateway)?;
+
+        Ok(Body::from_json(&status)?.into())
+    }
+
+    async fn reload_agent(req: Request<Arc<Server>>) -> tide::Result {
+        Self::call_agent(&req, AgentRequest::Reload).await?;
+
+        Ok(StatusCode::Ok.into())
+    }
+
+    async fn rollback_agent_task(req: Request<Arc<Server>>) -> tide::Result {
+        let task_id = Self::get_param(&req, "task_id")?;
+        let task_id = Uuid::parse_str(task_id).status(StatusCode::BadRequest)?;
+
+        let resp = Self::call_agent(&req, AgentRequest::Rollback { id: task_id }).await?;
+        let result: TaskResult = resp.into().status(StatusCode::BadGateway)?;
+
+        Ok(Body::from_json(&result)?.into())
+    }
+
     async fn get_agent_task(req: Request<Arc<Server>>) -> tide::Result {
         // parse params
         let agent_id = Self::get_param(&req, "agent_id")?;
@@ -0,0 +1,27 @@
+#!/bin/sh
+# Self-signed certificates for trying out tls locally:
+#   ca.pem / ca.key          the ca signing both ends
+#   server.pem / server.key  server certificate for localhost and 127.0.0.1
+#   agent.pem / agent.key    client certificate of the agent
+set -e
+
+DIR=${1:-.}
+DAYS=365
+mkdir -p "$DIR" && cd "$DIR"
+
+openssl req -x509 -newkey rsa:2048 -nodes -days $DAYS \
+    -keyout ca.key -out ca.pem -subj "/CN=file-agent ca"
+
+openssl req -newkey rsa:2048 -nodes \
+    -keyout server.key -out server.csr -subj "/CN=localhost"
+printf "subjectAltName=DNS:localhost,IP:127.0.0.1\nextendedKeyUsage=serverAuth\n" > server.ext
+openssl x509 -req -in server.csr -CA ca.pem -CAkey ca.key -CAcreateserial \
+    -days $DAYS -extfile server.ext -out server.pem
+
+openssl req -newkey rsa:2048 -nodes \
+    -keyout agent.key -out agent.csr -subj "/CN=file-agent agent"
+printf "extendedKeyUsage=clientAuth\n" > agent.ext
+openssl x509 -req -in agent.csr -CA ca.pem -CAkey ca.key -CAcreateserial \
+    -days $DAYS -extfile agent.ext -out agent.pem
+
+rm -f server.csr server.ext agent.csr agent.ext ca.srl